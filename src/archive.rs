@@ -0,0 +1,84 @@
+use crate::LockResultExt;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::SystemTime;
+use zip::ZipArchive;
+
+// A pack that is still zipped, like the .mcpack files people download
+#[derive(Debug, Clone)]
+pub struct PackArchive {
+    pub file: PathBuf,
+    // Folder inside the archive that has the manifest, empty or ending in /
+    pub prefix: String,
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("mcpack") || ext.eq_ignore_ascii_case("zip"))
+}
+
+pub type SharedArchive = Arc<Mutex<ZipArchive<File>>>;
+
+struct OpenArchive {
+    modified: SystemTime,
+    zip: SharedArchive,
+}
+// Archives we already read the central directory of, the game opens
+// assets one by one so reading it every time adds up
+static OPEN_ARCHIVES: LazyLock<Mutex<HashMap<PathBuf, OpenArchive>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn open(path: &Path) -> io::Result<SharedArchive> {
+    let modified = fs::metadata(path)?.modified()?;
+    if let Some(open) = OPEN_ARCHIVES.lock().ignore_poison().get(path) {
+        // Packs can get reimported while we have them open
+        if open.modified == modified {
+            return Ok(open.zip.clone());
+        }
+    }
+    let zip = ZipArchive::new(File::open(path)?).map_err(io::Error::other)?;
+    let zip = Arc::new(Mutex::new(zip));
+    let open = OpenArchive {
+        modified,
+        zip: zip.clone(),
+    };
+    OPEN_ARCHIVES
+        .lock()
+        .ignore_poison()
+        .insert(path.to_path_buf(), open);
+    Ok(zip)
+}
+
+// Close an archive that is not installed anymore
+pub fn forget(path: &Path) {
+    OPEN_ARCHIVES.lock().ignore_poison().remove(path);
+}
+
+impl PackArchive {
+    // Packs are sometimes zipped with their folder inside,
+    // so we use the manifest closest to the root
+    pub fn find(path: &Path, archive: &ZipArchive<File>) -> Option<Self> {
+        let manifest = archive
+            .file_names()
+            .filter(|name| *name == "manifest.json" || name.ends_with("/manifest.json"))
+            .min_by_key(|name| name.matches('/').count())?;
+        let prefix = &manifest[..manifest.len() - "manifest.json".len()];
+        Some(Self {
+            file: path.to_path_buf(),
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
+// Read a whole entry, there is no way to seek in compressed data anyways
+pub fn read_entry(archive_path: &Path, entry: &str) -> io::Result<Vec<u8>> {
+    let archive = open(archive_path)?;
+    let mut archive = archive.lock().ignore_poison();
+    let mut file = archive.by_name(entry).map_err(io::Error::other)?;
+    let mut data = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
@@ -0,0 +1,364 @@
+use crate::platform::Options;
+use materialbin::{
+    bgfx_shader::BgfxShader, pass::ShaderStage, CompiledMaterialDefinition, MinecraftVersion,
+    ALL_VERSIONS,
+};
+use memchr::memmem::Finder;
+use scroll::Pread;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// If a redirected resource is a material we can fix
+pub fn is_material(path: &Path) -> bool {
+    path.starts_with("renderer/materials/")
+        && path.as_os_str().as_bytes().ends_with(b".material.bin")
+}
+
+// First game version that uses each material format
+const FORMAT_VERSIONS: [([u32; 3], MinecraftVersion); 6] = [
+    ([1, 18, 30], MinecraftVersion::V1_18_30),
+    ([1, 19, 60], MinecraftVersion::V1_19_60),
+    ([1, 20, 80], MinecraftVersion::V1_20_80),
+    ([1, 21, 20], MinecraftVersion::V1_21_20),
+    ([1, 21, 110], MinecraftVersion::V1_21_110),
+    ([26, 0, 24], MinecraftVersion::V26_0_24),
+];
+
+/// Map a game version name like "1.21.60.10" to the material format it uses
+pub fn format_for_game_version(name: &str) -> Option<MinecraftVersion> {
+    let mut numbers = [0; 3];
+    for (number, part) in numbers.iter_mut().zip(name.split('.')) {
+        // Beta names can have junk after the numbers
+        let digits = part.split(|c: char| !c.is_ascii_digit()).next()?;
+        *number = digits.parse::<u32>().ok()?;
+    }
+    FORMAT_VERSIONS
+        .iter()
+        .rev()
+        .find(|(first, _)| *first <= numbers)
+        .map(|(_, format)| *format)
+}
+
+// Find out what format a material is in, we try newest first
+// since the old parsers are more lenient with new data
+pub fn detect_version(data: &[u8]) -> Option<MinecraftVersion> {
+    for version in ALL_VERSIONS.into_iter().rev() {
+        if data
+            .pread_with::<CompiledMaterialDefinition>(0, version)
+            .is_ok()
+        {
+            return Some(version);
+        }
+    }
+    None
+}
+
+/// Parse a material with the versions the user allowed and write it
+/// back in the format of `target`.
+/// Returns None if the material is fine as is or cannot be processed
+pub fn process_material(data: &[u8], target: MinecraftVersion, opts: &Options) -> Option<Vec<u8>> {
+    // Same order as detect_version, but a material that is already
+    // in the target format must not get picked up by an older parser
+    let versions = std::iter::once(target).chain(
+        ALL_VERSIONS
+            .into_iter()
+            .rev()
+            .filter(|v| *v != target && opts.autofixer_versions.contains(v)),
+    );
+    for version in versions {
+        let mut material: CompiledMaterialDefinition = match data.pread_with(0, version) {
+            Ok(material) => material,
+            Err(e) => {
+                log::trace!("[{version}] Parsing failed: {e}");
+                continue;
+            }
+        };
+        let mut changed = version != target;
+        if opts.handle_lightmaps {
+            changed |= handle_lightmaps(&mut material, version, target);
+        }
+        if opts.handle_texturelods && needs_texturelod_fix(&material, version, target) {
+            changed |= handle_texturelods(&mut material);
+        }
+        // Nothing to do here
+        if !changed {
+            log::info!("Material {} is already in {target} format", material.name);
+            return None;
+        }
+        let mut output = Vec::with_capacity(data.len());
+        if let Err(e) = material.write(&mut output, target) {
+            log::warn!("[{target}] Writing material {} failed: {e}", material.name);
+            return None;
+        }
+        log::info!(
+            "Updated material {} from {version} to {target}",
+            material.name
+        );
+        return Some(output);
+    }
+    log::warn!("Material could not be parsed with any autofixer version");
+    None
+}
+
+// The way the lightmap uv gets packed into a_texcoord1,
+// named after the lightmapUtil shims in assets
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LightmapEncoding {
+    // 10023: plain uv in xy
+    Uv,
+    // 11020: both coords packed in 8 bits of x
+    Packed8,
+    // 13028: both coords packed in 16 bits of y
+    Packed16,
+}
+impl LightmapEncoding {
+    // What the game feeds to shaders in a given version
+    fn of_version(version: MinecraftVersion) -> Self {
+        match version {
+            MinecraftVersion::V1_18_30
+            | MinecraftVersion::V1_19_60
+            | MinecraftVersion::V1_20_80 => Self::Uv,
+            MinecraftVersion::V1_21_20 | MinecraftVersion::V1_21_110 => Self::Packed8,
+            _ => Self::Packed16,
+        }
+    }
+    // Try to guess what the shader expects by looking at how it unpacks the uv
+    fn from_code(code: &[u8]) -> Option<Self> {
+        if Finder::new(b"65535.0").find(code).is_some() {
+            return Some(Self::Packed16);
+        }
+        if Finder::new(b"15.9375").find(code).is_some() {
+            return Some(Self::Packed8);
+        }
+        None
+    }
+}
+
+// Pick the shim that turns what the game gives into what the shader wants
+fn lightmap_shim(expected: LightmapEncoding, given: LightmapEncoding) -> Option<&'static str> {
+    use LightmapEncoding::*;
+    let shim = match (expected, given) {
+        (Uv, Packed8) => include_str!("../assets/lightmapUtil_10023_11020.glsl"),
+        (Uv, Packed16) => include_str!("../assets/lightmapUtil_10023_13028.glsl"),
+        (Packed8, Packed16) => include_str!("../assets/lightmapUtil_11020_13028.glsl"),
+        (Packed16, Packed8) => include_str!("../assets/lightmapUtil_13028_11020.glsl"),
+        _ => return None,
+    };
+    Some(shim)
+}
+
+// Returns true if any shader got changed
+fn handle_lightmaps(
+    material: &mut CompiledMaterialDefinition,
+    version: MinecraftVersion,
+    target: MinecraftVersion,
+) -> bool {
+    let given = LightmapEncoding::of_version(target);
+    let main_finder = Finder::new(b"void main");
+    let texcoord_finder = Finder::new(b"a_texcoord1");
+    let lightmap_finder = Finder::new(b"v_lightmapUV");
+    // Our own shims, or someone elses
+    let shimmed_finder = Finder::new(b"lightmapUtil_");
+    let changed = patch_shaders(
+        material,
+        // The shims need unsigned ints
+        |stage, platform| *stage == ShaderStage::Vertex && is_modern_glsl(platform),
+        |code| {
+            if texcoord_finder.find(code).is_none()
+                || lightmap_finder.find(code).is_none()
+                || shimmed_finder.find(code).is_some()
+            {
+                return false;
+            }
+            let expected = LightmapEncoding::from_code(code)
+                .unwrap_or_else(|| LightmapEncoding::of_version(version));
+            if expected == given {
+                return false;
+            }
+            let Some(shim) = lightmap_shim(expected, given) else {
+                log::warn!("No lightmap shim for {expected:?} from {given:?}");
+                return false;
+            };
+            insert_before(code, &main_finder, shim.as_bytes())
+        },
+    );
+    if changed {
+        log::info!("Fixed lightmaps for {} ({given:?})", material.name);
+    }
+    changed
+}
+
+// Renderdragon started sampling terrain with mipmaps after 1.19.60, old
+// shaders that sample with implicit lod end up reading garbage mips and
+// render black, so we force lod 0 like they used to get
+fn needs_texturelod_fix(
+    material: &CompiledMaterialDefinition,
+    version: MinecraftVersion,
+    target: MinecraftVersion,
+) -> bool {
+    material.name == "RenderChunk"
+        && version <= MinecraftVersion::V1_19_60
+        && target >= MinecraftVersion::V1_20_80
+}
+
+// Returns true if any shader got changed
+fn handle_texturelods(material: &mut CompiledMaterialDefinition) -> bool {
+    let changed = patch_shaders(
+        material,
+        |stage, platform| *stage == ShaderStage::Fragment && is_modern_glsl(platform),
+        force_texture_lod,
+    );
+    if changed {
+        log::info!("Fixed texture lods for {}", material.name);
+    }
+    changed
+}
+
+// Rewrite texture(s, uv) and texture(s, uv, bias) on 2D samplers
+// into textureLod(s, uv, 0.0), other sampler types are left alone
+// since not all of them have a textureLod
+fn force_texture_lod(code: &mut Vec<u8>) -> bool {
+    let Ok(text) = std::str::from_utf8(code) else {
+        return false;
+    };
+    let samplers = sampler2d_names(text);
+    if samplers.is_empty() {
+        return false;
+    }
+    let mut fixed = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut changed = false;
+    while let Some(start) = find_call(rest, "texture") {
+        fixed.push_str(&rest[..start]);
+        let call = &rest[start + "texture(".len()..];
+        match split_args(call) {
+            Some((args, len)) if (2..=3).contains(&args.len()) && samplers.contains(&args[0]) => {
+                fixed.push_str(&format!("textureLod({}, {}, 0.0)", args[0], args[1]));
+                rest = &call[len..];
+                changed = true;
+            }
+            _ => {
+                fixed.push_str("texture(");
+                rest = call;
+            }
+        }
+    }
+    fixed.push_str(rest);
+    if changed {
+        *code = fixed.into_bytes();
+    }
+    changed
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// Uniforms and parameters declared as sampler2D
+fn sampler2d_names(code: &str) -> Vec<&str> {
+    let mut tokens = code
+        .split(|c: char| !is_ident_char(c))
+        .filter(|t| !t.is_empty());
+    let mut names = Vec::new();
+    while let Some(token) = tokens.next() {
+        if token == "sampler2D" {
+            names.extend(tokens.next());
+        }
+    }
+    names
+}
+
+// Start of the next call to `name`, not counting calls to
+// functions that just end with it like my_texture(
+fn find_call(code: &str, name: &str) -> Option<usize> {
+    let pattern = format!("{name}(");
+    let mut offset = 0;
+    while let Some(found) = code[offset..].find(&pattern) {
+        let start = offset + found;
+        if !code[..start].ends_with(is_ident_char) {
+            return Some(start);
+        }
+        offset = start + pattern.len();
+    }
+    None
+}
+
+// Split the arguments of a call, `code` starts right after the (
+// Returns the trimmed arguments and the length up to and including the )
+fn split_args(code: &str) -> Option<(Vec<&str>, usize)> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut arg_start = 0;
+    for (i, c) in code.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' if depth > 0 => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(code[arg_start..i].trim());
+                arg_start = i + 1;
+            }
+            ')' => {
+                args.push(code[arg_start..i].trim());
+                return Some((args, i + 1));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// ESSL 300+ and GLSL 130+, older ones lack unsigned ints and
+// textureLod in fragment shaders, and the rest are not glsl at all
+fn is_modern_glsl(platform: &str) -> bool {
+    let version = |prefix: &str| {
+        platform
+            .strip_prefix(prefix)
+            .and_then(|version| version.parse::<u32>().ok())
+    };
+    version("ESSL_").is_some_and(|version| version >= 300)
+        || version("GLSL_").is_some_and(|version| version >= 130)
+}
+
+// Run `patch` on the bgfx code of every shader `filter` accepts
+// Returns true if any shader got changed
+fn patch_shaders(
+    material: &mut CompiledMaterialDefinition,
+    filter: impl Fn(&ShaderStage, &str) -> bool,
+    mut patch: impl FnMut(&mut Vec<u8>) -> bool,
+) -> bool {
+    let mut changed = false;
+    for (_, pass) in &mut material.passes {
+        for variant in &mut pass.variants {
+            for (stage, code) in &mut variant.shader_codes {
+                if !filter(&stage.stage, stage.platform_name.as_str()) {
+                    continue;
+                }
+                let blob = &mut code.bgfx_shader_data;
+                let Ok(mut bgfx) = blob.pread::<BgfxShader>(0) else {
+                    continue;
+                };
+                if !patch(&mut bgfx.code) {
+                    continue;
+                }
+                // Do not touch the blob until we know the write worked
+                let mut new_blob = Vec::with_capacity(blob.len());
+                if let Err(e) = bgfx.write(&mut new_blob) {
+                    log::warn!("Failed writing patched shader: {e}");
+                    continue;
+                }
+                *blob = new_blob;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// Splice `text` in front of the first match, returns false if nothing matched
+fn insert_before(code: &mut Vec<u8>, finder: &Finder, text: &[u8]) -> bool {
+    let Some(index) = finder.find(code) else {
+        return false;
+    };
+    code.splice(index..index, text.iter().chain(b"\n").copied());
+    true
+}
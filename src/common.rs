@@ -1,10 +1,13 @@
-use crate::mc_utils::{DataError, DataManager};
+use crate::mc_utils::{self, DataError, DataManager, ResourcePath};
 use crate::platform::android::{get_storage_location, get_storage_path};
 use crate::platform::storage::StorageLocation;
-use crate::SHADER_PATHS;
-use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
+use crate::{autofix, config, material_cache, platform, LockResultExt, SHADER_PATHS};
+use notify::event::{AccessKind, AccessMode};
+use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 pub static SHOULD_STOP: AtomicBool = AtomicBool::new(false);
 pub(crate) fn setup_json_watcher(path: PathBuf) {
@@ -12,8 +15,8 @@ pub(crate) fn setup_json_watcher(path: PathBuf) {
         Some(yayy) => yayy,
         None => StorageLocation::Internal,
     };
-    let path = get_storage_path(current_location);
-    let mut data_manager = setup_dataman(&path);
+    let storage_path = get_storage_path(current_location);
+    let mut data_manager = setup_dataman(&storage_path);
     if !data_manager.active_packs_path.exists() {
         data_manager.active_packs_path =
             setup_dataman(&get_storage_path(StorageLocation::Internal)).active_packs_path;
@@ -22,6 +25,12 @@ pub(crate) fn setup_json_watcher(path: PathBuf) {
         }
         log::info!("global packs json not found, defaulting to internal storage");
     }
+    let config_path = data_manager
+        .active_packs_path
+        .with_file_name(config::CONFIG_NAME);
+    load_config(&config_path);
+    // The config can change where we look for packs
+    data_manager.pack_roots = pack_roots(&storage_path);
     startup_load(&mut data_manager);
     let (sender, reciever) = std::sync::mpsc::channel();
     let mut watcher = RecommendedWatcher::new(sender, Config::default()).unwrap();
@@ -32,10 +41,34 @@ pub(crate) fn setup_json_watcher(path: PathBuf) {
             std::thread::sleep(Duration::from_secs(5));
         }
     }
+    // We watch the whole folder so that the config file can be created later
+    let watch_dir = data_manager
+        .active_packs_path
+        .parent()
+        .expect("The active packs file should be inside minecraftpe");
     watcher
-        .watch(&data_manager.active_packs_path, RecursiveMode::NonRecursive)
+        .watch(watch_dir, RecursiveMode::NonRecursive)
         .unwrap();
-    for event in reciever {
+    let mut worlds_dir = storage_path.clone();
+    worlds_dir.extend(["games", "com.mojang", "minecraftWorlds"]);
+    let mut worlds = WorldTracker::new(worlds_dir, &mut watcher);
+    loop {
+        // Only wait for as long as a guess about the world can stay unconfirmed
+        let event = match worlds.pending_timeout() {
+            Some(timeout) => reciever.recv_timeout(timeout),
+            None => reciever.recv().map_err(RecvTimeoutError::from),
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                if worlds.check_pending() {
+                    data_manager.world_dir = worlds.current.clone();
+                    reload_packs(&mut data_manager);
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
         let should_stop = SHOULD_STOP.load(Ordering::Acquire);
         if should_stop {
             // Something happened that requires us to stop this thread
@@ -49,7 +82,6 @@ pub(crate) fn setup_json_watcher(path: PathBuf) {
                 continue;
             }
         };
-        log::info!("Recieved interesting event: {:#?}", event);
         // Get the first filename in the event
         let Some(path) = event.paths.first() else {
             log::warn!("No event path found");
@@ -59,41 +91,238 @@ pub(crate) fn setup_json_watcher(path: PathBuf) {
             log::warn!("Event path has no filename");
             continue;
         };
+        if path.starts_with(&worlds.worlds_dir) {
+            if worlds.on_event(&event, path, &mut watcher) {
+                data_manager.world_dir = worlds.current.clone();
+                reload_packs(&mut data_manager);
+            }
+            continue;
+        }
+        if file_name == config::CONFIG_NAME {
+            if event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove() {
+                log::info!("Config changed, reloading..");
+                load_config(path);
+                data_manager.pack_roots = pack_roots(&storage_path);
+                // The redirects decide what files we pick from packs
+                data_manager.forget_files();
+                reload_packs(&mut data_manager);
+            }
+            continue;
+        }
+        // Other files in minecraftpe are none of our business
+        if file_name != "global_resource_packs.json" {
+            continue;
+        }
+        log::info!("Recieved interesting event: {:#?}", event);
 
         if &data_manager.active_packs_path != path {
             log::warn!("Wrong path detected, correcting..");
-            let new_dataman =
-                DataManager::init_data(path.clone(), data_manager.resourcepacks_dir.clone());
-            data_manager = new_dataman;
+            data_manager.active_packs_path = path.clone();
         }
         // This means that Minecraft has changed or read the resource list, let's do it too
         if file_name == "global_resource_packs.json" && event.kind.is_modify() {
             log::info!("Active rpacks changed, updating..");
+            reload_packs(&mut data_manager);
+        }
+    }
+}
+fn reload_packs(dataman: &mut DataManager) {
+    if let Err(e) = update_global_sp(dataman) {
+        log::warn!("Updating shader paths failed: {e}");
+    };
+    preconvert_materials();
+}
 
-            if let Err(e) = update_global_sp(&mut data_manager) {
-                log::warn!("Updating shader paths failed: {e}");
-            };
+// There is no way to ask the game what world it has open, so we
+// guess it from the files it opens in minecraftWorlds:
+// loading a world reads its world_resource_packs.json, and going back
+// to the world list reads the levelname.txt of every world.
+// The game also opens the levelname.txt of the loaded world to save
+// it, so opening that one only counts once no write follows.
+// If the game ever reads levelname.txt without showing the list we
+// will drop the world packs early, which is the safer mistake
+struct WorldTracker {
+    worlds_dir: PathBuf,
+    current: Option<PathBuf>,
+    entered_at: Instant,
+    // When the loaded world's levelname.txt got opened, if we
+    // are still waiting to see if the game writes to it
+    leave_pending: Option<Instant>,
+}
+// The world list is never shown this soon after picking a world,
+// anything the game reads from the world then is part of loading it
+const WORLD_LOAD_GRACE: Duration = Duration::from_secs(10);
+// A save writes to levelname.txt right after opening it
+const SAVE_WRITE_DELAY: Duration = Duration::from_secs(2);
+impl WorldTracker {
+    fn new(worlds_dir: PathBuf, watcher: &mut RecommendedWatcher) -> Self {
+        // Not recursive, or we would get an event for every leveldb write
+        if let Err(e) = watcher.watch(&worlds_dir, RecursiveMode::NonRecursive) {
+            log::warn!("Cannot watch worlds folder, world packs will not work: {e}");
+        }
+        if let Ok(entries) = std::fs::read_dir(&worlds_dir) {
+            for entry in entries.flatten() {
+                if entry.file_type().is_ok_and(|ftype| ftype.is_dir()) {
+                    let _ = watcher.watch(&entry.path(), RecursiveMode::NonRecursive);
+                }
+            }
+        }
+        Self {
+            worlds_dir,
+            current: None,
+            entered_at: Instant::now(),
+            leave_pending: None,
+        }
+    }
+    // How long until a pending leave has to be checked
+    fn pending_timeout(&self) -> Option<Duration> {
+        let opened = self.leave_pending?;
+        Some(SAVE_WRITE_DELAY.saturating_sub(opened.elapsed()))
+    }
+    // Returns true if we left the world because it was
+    // not a save that opened its levelname.txt
+    fn check_pending(&mut self) -> bool {
+        let Some(opened) = self.leave_pending else {
+            return false;
+        };
+        if opened.elapsed() < SAVE_WRITE_DELAY {
+            return false;
         }
+        log::info!("Left world, back to global packs");
+        self.leave_pending = None;
+        self.current = None;
+        true
+    }
+    // Returns true if the loaded world changed
+    fn on_event(&mut self, event: &Event, path: &Path, watcher: &mut RecommendedWatcher) -> bool {
+        let Some(world_name) = path
+            .strip_prefix(&self.worlds_dir)
+            .ok()
+            .and_then(|rest| rest.iter().next())
+        else {
+            return false;
+        };
+        let world = self.worlds_dir.join(world_name);
+        if path == world {
+            if event.kind.is_create() {
+                let _ = watcher.watch(&world, RecursiveMode::NonRecursive);
+            }
+            if event.kind.is_remove() && self.current.as_ref() == Some(&world) {
+                log::info!("Loaded world got deleted");
+                self.current = None;
+                self.leave_pending = None;
+                return true;
+            }
+            return false;
+        }
+        let is_current = self.current.as_ref() == Some(&world);
+        let Some(file_name) = path.file_name() else {
+            return false;
+        };
+        let is_write = matches!(
+            event.kind,
+            EventKind::Modify(_) | EventKind::Access(AccessKind::Close(AccessMode::Write))
+        );
+        if is_current && file_name == "levelname.txt" && is_write && self.leave_pending.is_some() {
+            log::debug!("Loaded world got saved");
+            self.leave_pending = None;
+            return false;
+        }
+        if !matches!(event.kind, EventKind::Access(AccessKind::Open(_))) {
+            return false;
+        }
+        if file_name == "world_resource_packs.json" {
+            // Going back into the world we just left keeps its packs
+            self.entered_at = Instant::now();
+            self.leave_pending = None;
+            if is_current {
+                return false;
+            }
+            log::info!("Loading world {world:?}");
+            self.current = Some(world);
+            return true;
+        }
+        if file_name != "levelname.txt" || self.current.is_none() {
+            return false;
+        }
+        if !is_current {
+            log::info!("Left world, back to global packs");
+            self.current = None;
+            self.leave_pending = None;
+            return true;
+        }
+        // With a single world the list only has the one we were in,
+        // but it could also be a save so wait and see
+        if self.entered_at.elapsed() > WORLD_LOAD_GRACE && self.leave_pending.is_none() {
+            self.leave_pending = Some(Instant::now());
+        }
+        false
     }
 }
 fn update_global_sp<'guh>(dataman: &'guh mut DataManager) -> Result<(), DataError> {
     let time = Instant::now();
 
-    let mut locked_sp = SHADER_PATHS.lock().unwrap_or_else(|err| err.into_inner()); //        .expect("The shader paths lock should never be poisoned");
+    // Build the new set first, the game cant open assets while we hold the lock
     let data = dataman.shader_paths()?;
-    // drop(dataman);
-    //
-
-    *locked_sp = data;
+    SHADER_PATHS.store(Arc::new(data));
     log::info!(
         "Updated global shader paths in {}ms...",
         time.elapsed().as_millis()
     );
     Ok(())
 }
+fn load_config(path: &Path) {
+    if !path.exists() {
+        log::info!("No config file found, using defaults");
+        config::reset();
+        return;
+    }
+    if let Err(e) = config::load(path) {
+        log::warn!("Loading config failed: {e}");
+    }
+}
 fn startup_load(dataman: &mut DataManager) {
     log::info!("Trying to load files eagerly");
     update_global_sp(dataman);
+    preconvert_materials();
+}
+// Run the autofixer on every redirected material now, so that
+// asset_open only has to read them back from the cache
+fn preconvert_materials() {
+    let Some(target) = platform::game_version() else {
+        log::info!("Game version is unknown, not preconverting materials");
+        return;
+    };
+    let time = Instant::now();
+    // Copy what we need so the game is not stuck waiting on us
+    let materials: Vec<ResourcePath> = SHADER_PATHS
+        .load()
+        .iter()
+        .filter(|path| autofix::is_material(path.resource_name()))
+        .cloned()
+        .collect();
+    let opts = platform::OPTS.lock().ignore_poison().clone();
+    if !(opts.autofix && opts.material_cache && opts.preconvert_materials) {
+        return;
+    }
+    for path in &materials {
+        if SHOULD_STOP.load(Ordering::Acquire) {
+            return;
+        }
+        let data = match mc_utils::read_resource(path) {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("Cannot read material {:?}: {e}", path.path());
+                continue;
+            }
+        };
+        material_cache::process_material(&data, target, &opts);
+    }
+    log::info!(
+        "Preconverted {} materials in {}ms...",
+        materials.len(),
+        time.elapsed().as_millis()
+    );
 }
 fn setup_dataman(mc_path: &Path) -> DataManager {
     let mut json_path = mc_path.to_path_buf();
@@ -103,7 +332,14 @@ fn setup_dataman(mc_path: &Path) -> DataManager {
         "minecraftpe",
         "global_resource_packs.json",
     ]);
-    let mut resourcepacks_path = mc_path.to_path_buf();
-    resourcepacks_path.extend(["games", "com.mojang", "resource_packs"]);
-    DataManager::init_data(json_path, resourcepacks_path)
+    DataManager::init_data(json_path, pack_roots(mc_path))
+}
+fn pack_roots(mc_path: &Path) -> Vec<PathBuf> {
+    let mut games_path = mc_path.to_path_buf();
+    games_path.extend(["games", "com.mojang"]);
+    let opts = platform::OPTS.lock().ignore_poison();
+    opts.pack_roots
+        .iter()
+        .map(|root| games_path.join(root))
+        .collect()
 }
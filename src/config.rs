@@ -0,0 +1,157 @@
+use crate::mc_utils::VersionMatch;
+use crate::platform::{version_from_string, Options, DEFAULT_LOG_LEVEL, JNI_OPTS, OPTS};
+use crate::redirect::{AssetOverrides, Redirect, RedirectTable, REDIRECTS};
+use crate::LockResultExt;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use struson::reader::{JsonReader, JsonStreamReader, ReaderError, ReaderSettings};
+
+// Lives next to global_resource_packs.json
+pub const CONFIG_NAME: &str = "mcbe_r.json";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(io::Error),
+    JsonParse(ReaderError),
+    InvalidValue(&'static str),
+}
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IoError(e) => write!(f, "Io error while reading config: {e}"),
+            Self::JsonParse(e) => write!(f, "Config parsing error: {e}"),
+            Self::InvalidValue(field) => write!(f, "Config field {field} has an invalid value"),
+        }
+    }
+}
+from_error!(IoError, io::Error, ConfigError);
+from_error!(JsonParse, ReaderError, ConfigError);
+
+// Load the config and apply it on top of the defaults and what the
+// launcher set, so removing a field from the file undoes it too
+pub fn load(path: &Path) -> Result<(), ConfigError> {
+    let file = File::open(path)?;
+    let mut settings = ReaderSettings::default();
+    settings.allow_comments = true;
+    let mut json = JsonStreamReader::new_custom(file, settings);
+    // Parse everything before applying so a broken file changes nothing
+    let mut opts = base_options();
+    let mut log_level = None;
+    let mut redirects = None;
+    let mut overrides = None;
+    json.begin_object()?;
+    while json.has_next()? {
+        match json.next_name()? {
+            "autofix" => opts.autofix = json.next_bool()?,
+            "material_cache" => opts.material_cache = json.next_bool()?,
+            "preconvert_materials" => opts.preconvert_materials = json.next_bool()?,
+            "lightmap_autofix" => opts.handle_lightmaps = json.next_bool()?,
+            "texturelod_autofix" => opts.handle_texturelods = json.next_bool()?,
+            "autofix_versions" => {
+                let mut versions = Vec::new();
+                json.begin_array()?;
+                while json.has_next()? {
+                    let Some(version) = version_from_string(&json.next_string()?) else {
+                        return Err(ConfigError::InvalidValue("autofix_versions"));
+                    };
+                    versions.push(version);
+                }
+                json.end_array()?;
+                opts.autofixer_versions = versions;
+            }
+            // An object of game prefix -> pack folder
+            "redirects" => {
+                let mut list = Vec::new();
+                json.begin_object()?;
+                while json.has_next()? {
+                    let game_prefix = json.next_name_owned()?;
+                    list.push(Redirect::new(game_prefix, json.next_string()?));
+                }
+                json.end_object()?;
+                redirects = Some(list);
+            }
+            "pack_roots" => {
+                let mut roots = Vec::new();
+                json.begin_array()?;
+                while json.has_next()? {
+                    roots.push(json.next_string()?);
+                }
+                json.end_array()?;
+                opts.pack_roots = roots;
+            }
+            "version_match" => {
+                let Some(version_match) = VersionMatch::from_name(&json.next_string()?) else {
+                    return Err(ConfigError::InvalidValue("version_match"));
+                };
+                opts.version_match = version_match;
+            }
+            "asset_overrides" => overrides = Some(parse_overrides(&mut json)?),
+            "log_level" => {
+                let Ok(level) = json.next_string()?.parse::<log::LevelFilter>() else {
+                    return Err(ConfigError::InvalidValue("log_level"));
+                };
+                log_level = Some(level);
+            }
+            _ => {
+                json.skip_value()?;
+            }
+        }
+    }
+    json.end_object()?;
+    *OPTS.lock().ignore_poison() = opts;
+    // Start from the defaults so removed entries go away too
+    let mut table = RedirectTable::default();
+    if let Some(redirects) = redirects {
+        table.extend(redirects);
+    }
+    table.set_overrides(overrides.unwrap_or_default());
+    REDIRECTS.store(Arc::new(table));
+    log::set_max_level(log_level.unwrap_or(DEFAULT_LOG_LEVEL));
+    log::info!("Loaded config from {path:?}");
+    Ok(())
+}
+
+// Undo what a config that got deleted did
+pub fn reset() {
+    *OPTS.lock().ignore_poison() = base_options();
+    REDIRECTS.store(Arc::new(RedirectTable::default()));
+    log::set_max_level(DEFAULT_LOG_LEVEL);
+}
+
+// The options we have without a config file
+fn base_options() -> Options {
+    let mut opts = Options::default();
+    JNI_OPTS.lock().ignore_poison().apply(&mut opts);
+    opts
+}
+
+// { "enabled": bool, "allow": [prefix], "deny": [prefix] }
+fn parse_overrides(json: &mut impl JsonReader) -> Result<AssetOverrides, ConfigError> {
+    let mut overrides = AssetOverrides::default();
+    json.begin_object()?;
+    while json.has_next()? {
+        match json.next_name()? {
+            "enabled" => overrides.enabled = json.next_bool()?,
+            "allow" => overrides.allow = parse_paths(json)?,
+            "deny" => overrides.deny = parse_paths(json)?,
+            _ => {
+                json.skip_value()?;
+            }
+        }
+    }
+    json.end_object()?;
+    Ok(overrides)
+}
+fn parse_paths(json: &mut impl JsonReader) -> Result<Vec<PathBuf>, ConfigError> {
+    let mut paths = Vec::new();
+    json.begin_array()?;
+    while json.has_next()? {
+        paths.push(PathBuf::from(json.next_string()?));
+    }
+    json.end_array()?;
+    Ok(paths)
+}
@@ -1,24 +1,45 @@
+// Tests run on the host, where nothing calls the hooks
+#![cfg_attr(test, allow(dead_code))]
+// Shared by every module with its own error enum, has to come before them
+macro_rules! from_error {
+    ($dis:ident, $errorType:ty, $targetError:ty) => {
+        impl From<$errorType> for $targetError {
+            fn from(value: $errorType) -> Self {
+                Self::$dis(value)
+            }
+        }
+    };
+}
+mod archive;
+mod autofix;
 mod common;
+mod config;
 mod hooking;
+mod material_cache;
 mod mc_utils;
 mod platform;
+mod redirect;
 //use once_cell::sync::Lazy;
 use std::collections::{HashMap, HashSet};
 
 use std::fs;
 use std::path::PathBuf;
-use std::sync::{LazyLock, LockResult, Mutex};
+use std::sync::{LazyLock, LockResult};
 use std::time::Instant;
 
 use thread_priority::ThreadBuilderExt;
 
 use crate::mc_utils::ResourcePath;
+use arc_swap::ArcSwap;
 
-static SHADER_PATHS: LazyLock<Mutex<HashSet<ResourcePath<'static>>>> =
-    LazyLock::new(|| Mutex::new(HashSet::new()));
+// Swapped as a whole by the watcher, so asset_open never waits for a rebuild
+static SHADER_PATHS: LazyLock<ArcSwap<HashSet<ResourcePath<'static>>>> =
+    LazyLock::new(|| ArcSwap::from_pointee(HashSet::new()));
 
 // A quick startpoint for the library, mostly there because
-// unwinding up here is ub, + give a good panic message
+// unwinding up here is ub, + give a good panic message.
+// Test binaries are not the game, so they must not hook anything
+#[cfg(not(test))]
 ctor::declarative::ctor! {
   #[ctor]
   fn ctor() {
@@ -0,0 +1,212 @@
+use crate::autofix;
+use crate::platform::Options;
+use materialbin::MinecraftVersion;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+// Bump this when the autofixer output changes for the same input
+const CACHE_VERSION: u32 = 3;
+// Entries nobody used for this long get removed on startup
+const MAX_UNUSED_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 14);
+
+// Stored instead of a material when the autofixer did not change it
+const UNCHANGED_MARKER: &[u8] = b"mcbe_r:unchanged";
+// Every entry starts with the length and hash of its source
+const HEADER_LEN: usize = 8 + 16;
+
+static CACHE: OnceLock<MaterialCache> = OnceLock::new();
+// Makes tmp file names unique, the watcher and the game can
+// convert the same material at the same time
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Converted materials stored on disk, so that we only have
+// to run the autofixer once for each version of a pack file
+pub struct MaterialCache {
+    dir: PathBuf,
+}
+
+// 128 bit FNV-1a, unlike std hashers its output never changes
+// between builds, which matters for files that outlive them
+struct StableHasher(u128);
+impl StableHasher {
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+    fn new() -> Self {
+        Self(Self::OFFSET)
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u128::from(*byte);
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+    // Length first so "ab" + "c" and "a" + "bc" differ
+    fn write_str(&mut self, text: &str) {
+        self.write(&(text.len() as u64).to_le_bytes());
+        self.write(text.as_bytes());
+    }
+    fn finish(&self) -> u128 {
+        self.0
+    }
+}
+
+// Identifies the pack file a cache entry was made from
+#[derive(PartialEq)]
+struct SourceInfo {
+    len: u64,
+    hash: u128,
+}
+impl SourceInfo {
+    fn of(data: &[u8]) -> Self {
+        let mut hasher = StableHasher::new();
+        hasher.write(data);
+        Self {
+            len: data.len() as u64,
+            hash: hasher.finish(),
+        }
+    }
+    fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..8].copy_from_slice(&self.len.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.hash.to_le_bytes());
+        bytes
+    }
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let len = u64::from_le_bytes(bytes.get(..8)?.try_into().ok()?);
+        let hash = u128::from_le_bytes(bytes.get(8..HEADER_LEN)?.try_into().ok()?);
+        Some(Self { len, hash })
+    }
+}
+
+// What we know about a material
+enum CacheEntry {
+    // The autofixer did not need to touch it
+    Unchanged,
+    Converted(Vec<u8>),
+}
+
+impl MaterialCache {
+    fn open(dir: PathBuf) -> Self {
+        if let Err(e) = fs::create_dir_all(&dir) {
+            log::warn!("Cannot create material cache dir: {e}");
+        }
+        let cache = Self { dir };
+        cache.prune();
+        cache
+    }
+    // The source data is part of the key, so when a pack file
+    // changes its old entry just stops being used
+    fn key(source: &SourceInfo, target: MinecraftVersion, opts: &Options) -> String {
+        let mut hasher = StableHasher::new();
+        hasher.write(&CACHE_VERSION.to_le_bytes());
+        hasher.write_str(env!("CARGO_PKG_VERSION"));
+        hasher.write(&source.to_bytes());
+        hasher.write_str(&target.to_string());
+        hasher.write(&[opts.handle_lightmaps as u8, opts.handle_texturelods as u8]);
+        for version in &opts.autofixer_versions {
+            hasher.write_str(&version.to_string());
+        }
+        format!("{:032x}", hasher.finish())
+    }
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.material.bin"))
+    }
+    fn get(&self, key: &str, source: &SourceInfo) -> Option<CacheEntry> {
+        let mut file = File::open(self.entry_path(key)).ok()?;
+        let mut data = Vec::new();
+        if let Err(e) = file.read_to_end(&mut data) {
+            log::warn!("Cannot read cached material: {e}");
+            return None;
+        }
+        // The key is only a hash, make sure this entry
+        // really was made from the same file
+        if SourceInfo::from_bytes(&data).as_ref() != Some(source) {
+            log::warn!("Cached material {key} belongs to another file");
+            return None;
+        }
+        data.drain(..HEADER_LEN);
+        // Keep it from getting pruned
+        let _ = file.set_modified(SystemTime::now());
+        if data == UNCHANGED_MARKER {
+            return Some(CacheEntry::Unchanged);
+        }
+        // Nothing we write is empty, so this is broken
+        if data.is_empty() {
+            return None;
+        }
+        Some(CacheEntry::Converted(data))
+    }
+    fn store(&self, key: &str, source: &SourceInfo, converted: Option<&[u8]>) {
+        let path = self.entry_path(key);
+        // Write somewhere else first so a crash cant leave half a file behind
+        let tmp_id = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = self
+            .dir
+            .join(format!("{key}.{}.{tmp_id}.tmp", std::process::id()));
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(&source.to_bytes())?;
+                file.write_all(converted.unwrap_or(UNCHANGED_MARKER))
+            })
+            .and_then(|_| fs::rename(&tmp_path, &path));
+        if let Err(e) = result {
+            log::warn!("Cannot store converted material: {e}");
+            let _ = fs::remove_file(&tmp_path);
+        }
+    }
+    fn prune(&self) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("Cannot read material cache dir: {e}");
+                return;
+            }
+        };
+        let now = SystemTime::now();
+        for entry in entries.flatten() {
+            let is_old = entry
+                .metadata()
+                .and_then(|meta| meta.modified())
+                .map(|modified| now.duration_since(modified).unwrap_or_default() > MAX_UNUSED_AGE)
+                .unwrap_or(false);
+            if is_old {
+                log::info!("Pruning cached material: {:?}", entry.file_name());
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+fn cache() -> Option<&'static MaterialCache> {
+    if let Some(cache) = CACHE.get() {
+        return Some(cache);
+    }
+    // We cant cache anything before java tells us where to
+    let dir = crate::platform::get_data_dir()?.join("material_cache");
+    Some(CACHE.get_or_init(|| MaterialCache::open(dir)))
+}
+
+/// Same as [`autofix::process_material`] but only does the work
+/// if the cache does not know the result already
+pub fn process_material(data: &[u8], target: MinecraftVersion, opts: &Options) -> Option<Vec<u8>> {
+    let Some(cache) = cache() else {
+        return autofix::process_material(data, target, opts);
+    };
+    let source = SourceInfo::of(data);
+    let key = MaterialCache::key(&source, target, opts);
+    match cache.get(&key, &source) {
+        Some(CacheEntry::Converted(converted)) => {
+            log::info!("Using cached material {key}");
+            return Some(converted);
+        }
+        Some(CacheEntry::Unchanged) => return None,
+        None => {}
+    }
+    let converted = autofix::process_material(data, target, opts);
+    cache.store(&key, &source, converted.as_deref());
+    converted
+}
@@ -6,20 +6,66 @@ use std::ffi::{CStr, OsStr};
 use std::fmt::Display;
 use std::fs::File;
 use std::hash::Hash;
+use std::io::Read;
 use std::num::ParseIntError;
 use std::ops::Range;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::SystemTime;
 use std::{fmt, fs, io};
-use struson::json_path;
-use struson::reader::{JsonReader, JsonStreamReader, ReaderError, ReaderSettings};
+use struson::reader::{JsonReader, JsonStreamReader, ReaderError, ReaderSettings, ValueType};
 // use tinyjson::{JsonParseError, JsonParser, JsonValue};
+use crate::archive::{self, PackArchive};
+use crate::redirect::{RedirectTable, REDIRECTS};
+use crate::LockResultExt;
 use walkdir::DirEntry;
 // Keeps track and manages data about the minecraft Resource Pack Structure
-#[derive(Debug)]
 pub struct DataManager {
-    pub resourcepacks_dir: PathBuf,
+    // Folders with installed packs, in the order we search them
+    pub pack_roots: Vec<PathBuf>,
     pub active_packs_path: PathBuf,
+    // The world that is loaded, its packs go on top of the global ones
+    pub world_dir: Option<PathBuf>,
+    index: PackIndex,
+}
+
+// What we found in pack roots last time, so a rebuild only
+// has to parse manifests that changed since then
+#[derive(Default)]
+struct PackIndex {
+    entries: Vec<IndexEntry>,
+    files: FileCache,
+}
+// A folder or archive in a pack root
+struct IndexEntry {
+    path: PathBuf,
+    // For folders this only changes when something is added or removed
+    // at the top, so it is only good enough to know the manifest is the same
+    modified: SystemTime,
+    // The manifest can be edited without touching the folder
+    manifest_modified: Option<SystemTime>,
+    pack: ValidPack,
+}
+// Redirected files of each pack folder we walked
+type FileCache = HashMap<(PathBuf, PathBuf), WalkedFiles>;
+struct WalkedFiles {
+    // None if the folder does not exist
+    files: Option<Vec<ResourcePath<'static>>>,
+    // Every directory we looked at and its mtime. Adding or removing a file
+    // only touches the mtime of the directory it is in, so this has to have
+    // all of them. Zipped packs change as a whole, so they have none
+    dirs: Vec<(PathBuf, Option<SystemTime>)>,
+}
+impl WalkedFiles {
+    fn is_current(&self) -> bool {
+        self.dirs
+            .iter()
+            .all(|(dir, modified)| dir_modified(dir) == *modified)
+    }
+}
+fn dir_modified(dir: &Path) -> Option<SystemTime> {
+    fs::metadata(dir).and_then(|meta| meta.modified()).ok()
 }
 
 // A pack that minecraft verified as valid
@@ -28,17 +74,17 @@ pub struct ValidPack {
     uuid: String,
     path: PathBuf,
     version: Vec<u32>,
+    // 1 and 2 use arrays for versions, 3 also allows semver strings
+    pub format_version: u32,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    // Oldest game version the pack was made for
+    pub min_engine_version: Option<Vec<u32>>,
+    subpacks: Vec<Subpack>,
+    // Set if the pack was never extracted
+    archive: Option<PackArchive>,
 }
 
-macro_rules! from_error {
-    ($dis:ident, $errorType:ty, $targetError:ty) => {
-        impl From<$errorType> for $targetError {
-            fn from(value: $errorType) -> Self {
-                Self::$dis(value)
-            }
-        }
-    };
-}
 from_error!(IoError, std::io::Error, PackParseError);
 from_error!(JsonParse, ReaderError, PackParseError);
 from_error!(VersionParse, ParseIntError, PackParseError);
@@ -69,63 +115,303 @@ impl ValidPack {
     // than bedrock in terms of json parsing
     fn parse_manifest(pack_path: PathBuf) -> Result<Self, PackParseError> {
         let manifest = File::open(pack_path.join("manifest.json"))?;
+        Self::parse_manifest_from(manifest, pack_path, None)
+    }
+    fn parse_archive(archive_path: &Path) -> Result<Self, PackParseError> {
+        let zip = archive::open(archive_path)?;
+        let mut zip = zip.lock().ignore_poison();
+        let Some(archive) = PackArchive::find(archive_path, &zip) else {
+            return Err(PackParseError::InvalidManifest);
+        };
+        let manifest_name = format!("{}manifest.json", archive.prefix);
+        let manifest = zip.by_name(&manifest_name).map_err(io::Error::other)?;
+        // This path is only used for logs
+        let pack_path = archive_path.join(&archive.prefix);
+        Self::parse_manifest_from(manifest, pack_path, Some(archive))
+    }
+    fn parse_manifest_from(
+        manifest: impl Read,
+        pack_path: PathBuf,
+        archive: Option<PackArchive>,
+    ) -> Result<Self, PackParseError> {
         let mut settings = ReaderSettings::default();
         settings.allow_comments = true;
         let mut json = JsonStreamReader::new_custom(manifest, settings);
-        json.seek_to(&json_path!["header"])?;
-        json.begin_object()?;
         let mut uuid = None;
         let mut version = None;
-        loop {
+        let mut format_version = 1;
+        let mut name = None;
+        let mut description = None;
+        let mut min_engine_version = None;
+        let mut subpacks = Vec::new();
+        json.begin_object()?;
+        while json.has_next()? {
             match json.next_name()? {
-                "uuid" => uuid = Some(json.next_string()?),
-                "version" => {
+                "format_version" => format_version = json.next_number()??,
+                "header" => {
+                    json.begin_object()?;
+                    while json.has_next()? {
+                        match json.next_name()? {
+                            "uuid" => uuid = Some(json.next_string()?),
+                            "name" => name = Some(json.next_string()?),
+                            "description" => description = Some(json.next_string()?),
+                            "min_engine_version" => {
+                                let min_version = parse_version::<PackParseError>(&mut json)?;
+                                min_engine_version = Some(min_version);
+                            }
+                            "version" => {
+                                version = Some(parse_version::<PackParseError>(&mut json)?)
+                            }
+                            _ => {
+                                json.skip_value()?;
+                            }
+                        }
+                    }
+                    json.end_object()?;
+                }
+                "subpacks" => {
                     json.begin_array()?;
-                    let mut numbers: Vec<u32> = Vec::new();
                     while json.has_next()? {
-                        let workaround = json.next_number()?;
-                        numbers.push(workaround?);
+                        json.begin_object()?;
+                        if let Some(subpack) = Subpack::parse(&mut json)? {
+                            subpacks.push(subpack);
+                        }
+                        json.end_object()?;
                     }
                     json.end_array()?;
-                    version = Some(numbers);
                 }
                 _ => {
                     json.skip_value()?;
                 }
             }
-            if !json.has_next()? {
-                break;
-            }
         }
         json.end_object()?;
         if uuid.is_none() || version.is_none() {
             return Err(PackParseError::InvalidManifest);
         }
+        if !(1..=3).contains(&format_version) {
+            log::warn!("Pack {pack_path:?} has unknown manifest format {format_version}");
+        }
         Ok(Self {
             uuid: uuid.unwrap(),
             path: pack_path,
 
             version: version.unwrap(),
+            format_version,
+            name,
+            description,
+            min_engine_version,
+            subpacks,
+            archive,
         })
     }
-    pub fn get_pack_files(&self, subpack: Option<String>, set: &mut HashSet<ResourcePath>) {
+    fn get_pack_files(
+        &self,
+        subpack: Option<String>,
+        redirects: &RedirectTable,
+        files: &mut FileCache,
+        set: &mut HashSet<ResourcePath>,
+    ) {
         // We add the subpack first as it has priority over main pack
-        if let Some(subpack) = subpack {
-            let mut path = self.path.to_path_buf();
-            path.extend(["subpacks", &subpack]);
-            get_files(&path, set);
-            //            files.extend(subpack_files);
+        if let Some(subpack) = subpack.and_then(|name| self.select_subpack(&name)) {
+            // folder_name can have more than one folder in it
+            let folder = Path::new("subpacks").join(&subpack.folder_name);
+            if !self.add_files(&folder, redirects, files, set) {
+                log::warn!("Subpack folder {folder:?} does not exist, using main pack only");
+            }
         }
         // Any files that the subpack has will override these
-        get_files(&self.path, set);
+        self.add_files(Path::new(""), redirects, files, set);
+    }
+    // Packs made for newer versions can use things this version lacks
+    pub fn supports_game(&self, game_version: &[u32]) -> bool {
+        match &self.min_engine_version {
+            Some(min_version) => game_version >= min_version.as_slice(),
+            None => true,
+        }
+    }
+    // Names are often keys like pack.name that only mean
+    // something with the texts of the pack
+    pub fn display_name(&self, locale: &str) -> String {
+        let Some(name) = &self.name else {
+            return self.uuid.clone();
+        };
+        if name.contains(char::is_whitespace) {
+            return name.clone();
+        }
+        // Packs that are not translated usually only have en_US
+        for lang in [locale, "en_US"] {
+            let Ok(data) = self.read_file(&format!("texts/{lang}.lang")) else {
+                continue;
+            };
+            if let Some(text) = find_lang_key(&String::from_utf8_lossy(&data), name) {
+                return text.to_string();
+            }
+        }
+        name.clone()
+    }
+    fn read_file(&self, file: &str) -> io::Result<Vec<u8>> {
+        match &self.archive {
+            Some(archive) => {
+                archive::read_entry(&archive.file, &format!("{}{file}", archive.prefix))
+            }
+            None => fs::read(self.path.join(file)),
+        }
+    }
+    // Returns false if the folder does not exist in the pack
+    fn add_files(
+        &self,
+        folder: &Path,
+        redirects: &RedirectTable,
+        files: &mut FileCache,
+        set: &mut HashSet<ResourcePath>,
+    ) -> bool {
+        let key = (self.path.clone(), folder.to_path_buf());
+        if !files.get(&key).is_some_and(WalkedFiles::is_current) {
+            let walked = self.find_files(folder, redirects);
+            files.insert(key.clone(), walked);
+        }
+        let Some(found) = &files[&key].files else {
+            return false;
+        };
+        for path in found {
+            set.insert(path.clone());
+        }
+        true
+    }
+    fn find_files(&self, folder: &Path, redirects: &RedirectTable) -> WalkedFiles {
+        let mut found = HashSet::new();
+        let Some(archive) = &self.archive else {
+            let path = self.path.join(folder);
+            // The folder showing up later changes the mtime of its parent
+            let mut dirs: Vec<_> = path
+                .ancestors()
+                .skip(1)
+                .take(folder.components().count())
+                .map(|dir| (dir.to_path_buf(), dir_modified(dir)))
+                .collect();
+            let files = path.is_dir().then(|| {
+                get_files(&path, redirects, &mut found, &mut dirs);
+                found.into_iter().collect()
+            });
+            return WalkedFiles { files, dirs };
+        };
+        let files = match get_archive_files(archive, folder, redirects, &mut found) {
+            Ok(exists) => exists.then(|| found.into_iter().collect()),
+            Err(e) => {
+                log::warn!("Cannot read pack archive {:?}: {e}", archive.file);
+                None
+            }
+        };
+        WalkedFiles {
+            files,
+            dirs: Vec::new(),
+        }
+    }
+    // Pick the subpack the game would use for this selection
+    fn select_subpack(&self, requested: &str) -> Option<&Subpack> {
+        let Some(index) = self
+            .subpacks
+            .iter()
+            .position(|subpack| subpack.folder_name == requested)
+        else {
+            log::warn!(
+                "Subpack {requested} is not in the manifest of {}, using main pack only",
+                self.uuid
+            );
+            return None;
+        };
+        // The game already checked this selection against the device before
+        // saving it, so we only complain if it looks off, and use it anyways
+        let subpack = &self.subpacks[index];
+        if let Some(device_tier) = device_memory_tier() {
+            if subpack.memory_tier.unwrap_or(0) > device_tier {
+                log::warn!(
+                    "Subpack {} wants memory tier {:?} (performance {:?}), device is tier {device_tier}",
+                    subpack.name.as_deref().unwrap_or(&subpack.folder_name),
+                    subpack.memory_tier,
+                    subpack.memory_performance_tier
+                );
+            }
+        }
+        Some(subpack)
+    }
+}
+
+// A subpack entry from a manifest
+#[derive(Debug)]
+struct Subpack {
+    folder_name: String,
+    name: Option<String>,
+    memory_tier: Option<u32>,
+    memory_performance_tier: Option<u32>,
+}
+impl Subpack {
+    // Entries without a folder are skipped like the game does
+    fn parse(json: &mut impl JsonReader) -> Result<Option<Self>, PackParseError> {
+        let mut folder_name = None;
+        let mut name = None;
+        let mut memory_tier = None;
+        let mut memory_performance_tier = None;
+        while json.has_next()? {
+            match json.next_name()? {
+                "folder_name" => folder_name = Some(json.next_string()?),
+                "name" => name = Some(json.next_string()?),
+                "memory_tier" => memory_tier = Some(json.next_number()??),
+                "memory_performance_tier" => memory_performance_tier = Some(json.next_number()??),
+                _ => {
+                    json.skip_value()?;
+                }
+            }
+        }
+        let Some(folder_name) = folder_name else {
+            log::warn!("Subpack {name:?} has no folder_name, skipping");
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            folder_name,
+            name,
+            memory_tier,
+            memory_performance_tier,
+        }))
+    }
+}
+
+// Each memory_tier is a quarter of a gigabyte
+fn device_memory_tier() -> Option<u32> {
+    static TIER: OnceLock<Option<u32>> = OnceLock::new();
+    *TIER.get_or_init(|| {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        let tier = total_memory().map(|bytes| (bytes / (GIB / 4)) as u32);
+        log::info!("Device memory tier: {tier:?}");
+        tier
+    })
+}
+fn total_memory() -> Option<u64> {
+    let mut info: libc::sysinfo = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sysinfo(&mut info) } != 0 {
+        return None;
     }
+    Some(info.totalram as u64 * info.mem_unit as u64)
 }
 
-fn get_files(path: &Path, file_list: &mut HashSet<ResourcePath>) {
+fn get_files(
+    path: &Path,
+    redirects: &RedirectTable,
+    file_list: &mut HashSet<ResourcePath>,
+    dirs: &mut Vec<(PathBuf, Option<SystemTime>)>,
+) {
     let walker = walkdir::WalkDir::new(path);
-    let iter = walker.into_iter().filter_entry(is_interesting).flatten();
+    let iter = walker
+        .into_iter()
+        .filter_entry(|entry| is_interesting(entry, redirects))
+        .flatten();
     //    let mut files = HashMap::new();
     for entry in iter {
+        if entry.file_type().is_dir() {
+            let modified = entry.metadata().ok().and_then(|meta| meta.modified().ok());
+            dirs.push((entry.path().to_path_buf(), modified));
+        }
         let curr_path = entry.into_path();
         let Some(resource_path) = ResourcePath::new(curr_path, &path) else {
             continue;
@@ -135,6 +421,46 @@ fn get_files(path: &Path, file_list: &mut HashSet<ResourcePath>) {
     //    files
 }
 
+// Same as get_files, but for a zipped pack
+fn get_archive_files(
+    archive: &PackArchive,
+    folder: &Path,
+    redirects: &RedirectTable,
+    file_list: &mut HashSet<ResourcePath>,
+) -> io::Result<bool> {
+    let zip = archive::open(&archive.file)?;
+    let zip = zip.lock().ignore_poison();
+    let root = Path::new(&archive.prefix).join(folder);
+    let mut found = false;
+    for name in zip.file_names() {
+        let Ok(resource) = Path::new(name).strip_prefix(&root) else {
+            continue;
+        };
+        found = true;
+        // Folders are entries too, but we only care about files
+        if name.ends_with('/') {
+            continue;
+        }
+        let Some(top_folder) = resource.iter().next() else {
+            continue;
+        };
+        if !redirects.is_pack_folder(top_folder) {
+            continue;
+        }
+        let resource_len = resource.as_os_str().len();
+        file_list.insert(ResourcePath::new_archive(&archive.file, name, resource_len));
+    }
+    Ok(found)
+}
+
+/// Read a whole resource, wherever it is
+pub fn read_resource(resource: &ResourcePath) -> io::Result<Vec<u8>> {
+    match resource.archive_entry() {
+        Some((archive, entry)) => archive::read_entry(archive, entry),
+        None => fs::read(resource.path()),
+    }
+}
+
 fn wrapping_sub_ptr<T>(lhs: *const T, rhs: *const T) -> usize {
     let pointee_size = std::mem::size_of::<T>();
     (lhs as usize - rhs as usize) / pointee_size
@@ -150,9 +476,12 @@ pub fn range_of<T>(outer: &[T], inner: &[T]) -> Option<Range<usize>> {
     }
 }
 
+#[derive(Clone)]
 pub struct ResourcePath<'a> {
     path: Cow<'a, Path>,
     resource_start: Range<usize>,
+    // For zipped packs the path is archive/entry, this is the archive part
+    archive_len: Option<usize>,
 }
 impl<'a> ResourcePath<'a> {
     pub fn new_nameless(path: Cow<'a, Path>) -> Self {
@@ -160,6 +489,17 @@ impl<'a> ResourcePath<'a> {
         Self {
             path,
             resource_start: 0..len,
+            archive_len: None,
+        }
+    }
+    pub fn new_archive(archive: &Path, entry: &str, resource_len: usize) -> Self {
+        let path = archive.join(entry);
+        let len = path.as_os_str().len();
+        Self {
+            path: Cow::Owned(path),
+            // The resource name is always at the end of the entry name
+            resource_start: len - resource_len..len,
+            archive_len: Some(archive.as_os_str().len()),
         }
     }
     pub fn new(path: PathBuf, prefix: &Path) -> Option<Self> {
@@ -169,8 +509,18 @@ impl<'a> ResourcePath<'a> {
         Some(Self {
             path: Cow::Owned(path),
             resource_start: range,
+            archive_len: None,
         })
     }
+    /// The archive and the entry name inside of it, if this is in a zipped pack
+    pub fn archive_entry(&self) -> Option<(&Path, &str)> {
+        let len = self.archive_len?;
+        let bytes = self.path.as_os_str().as_bytes();
+        let archive = Path::new(OsStr::from_bytes(&bytes[..len]));
+        // Skip the separator join added
+        let entry = std::str::from_utf8(&bytes[len + 1..]).ok()?;
+        Some((archive, entry))
+    }
     pub fn path(&self) -> &Path {
         self.path.as_ref()
     }
@@ -199,15 +549,53 @@ impl<'a> PartialEq for ResourcePath<'a> {
 }
 impl<'a> Eq for ResourcePath<'a> {}
 //impl Eq for ResourcePath {}
-fn is_interesting(entry: &DirEntry) -> bool {
+fn is_interesting(entry: &DirEntry, redirects: &RedirectTable) -> bool {
     if entry.depth() == 1 {
-        return entry.file_name() == "renderer"
-            || entry.file_name() == "vanilla_cameras"
-            || entry.file_name() == "hbui"
-            || entry.file_name() == "custom_persona";
+        return redirects.is_pack_folder(entry.file_name());
     }
     true
 }
+// Lines are key=value, and ## starts a comment
+fn find_lang_key<'a>(lang: &'a str, wanted: &str) -> Option<&'a str> {
+    for line in lang.lines() {
+        let line = line.trim_start_matches('\u{feff}');
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if key.trim() != wanted {
+            continue;
+        }
+        let value = value.split("##").next().unwrap_or_default();
+        // Some packs use a tab and a single # for comments
+        let value = value.split("\t#").next().unwrap_or_default();
+        return Some(value.trim());
+    }
+    None
+}
+// Versions are either [1, 2, 3] or "1.2.3" in newer manifests
+fn parse_version<E>(json: &mut impl JsonReader) -> Result<Vec<u32>, E>
+where
+    E: From<ReaderError> + From<ParseIntError>,
+{
+    if json.peek()? == ValueType::String {
+        let string = json.next_string()?;
+        // Prerelease and build tags do not matter to us
+        let core = string.split(['-', '+']).next().unwrap_or_default();
+        let numbers = core
+            .split('.')
+            .map(str::parse::<u32>)
+            .collect::<Result<_, _>>()?;
+        return Ok(numbers);
+    }
+    json.begin_array()?;
+    let mut numbers: Vec<u32> = Vec::new();
+    while json.has_next()? {
+        let workaround = json.next_number()?;
+        numbers.push(workaround?);
+    }
+    json.end_array()?;
+    Ok(numbers)
+}
 // A active global pack
 #[derive(Debug)]
 struct GlobalPack {
@@ -239,16 +627,7 @@ impl GlobalPack {
             match json.next_name()? {
                 "pack_id" => pack_id = Some(json.next_string()?),
                 "subpack" => subpack = Some(json.next_string()?),
-                "version" => {
-                    json.begin_array()?;
-                    let mut numbers: Vec<u32> = Vec::new();
-                    while json.has_next()? {
-                        let workaround = json.next_number()?;
-                        numbers.push(workaround?);
-                    }
-                    json.end_array()?;
-                    version = Some(numbers);
-                }
+                "version" => version = Some(parse_version::<DataError>(json)?),
                 _ => {
                     json.skip_value()?;
                 }
@@ -296,68 +675,236 @@ from_error!(JsonParse, ReaderError, DataError);
 from_error!(IntConvert, ParseIntError, DataError);
 impl DataManager {
     // Get minecraft paths and create itself
-    pub fn init_data(json_path: PathBuf, resourcepacks_path: PathBuf) -> Self {
+    pub fn init_data(json_path: PathBuf, pack_roots: Vec<PathBuf>) -> Self {
         Self {
-            resourcepacks_dir: resourcepacks_path,
+            pack_roots,
             active_packs_path: json_path,
+            world_dir: None,
+            index: PackIndex::default(),
         }
     }
+    // The files we pick from packs depend on the redirects
+    pub fn forget_files(&mut self) {
+        self.index.files.clear();
+    }
 
     // Get a list of shader paths
-    pub fn shader_paths<'a>(&self) -> Result<HashSet<ResourcePath<'a>>, DataError> {
+    pub fn shader_paths<'a>(&mut self) -> Result<HashSet<ResourcePath<'a>>, DataError> {
         let global_packs: Vec<GlobalPack> = GlobalPack::parse(&self.active_packs_path)?;
         log::info!("global_packs parsed: {:#?}", global_packs);
-        let packs = self.get_installed_packs()?;
-        log::info!("Installed packs: {packs:#?}");
+        let world_packs = match &self.world_dir {
+            Some(world) => match GlobalPack::parse(&world.join("world_resource_packs.json")) {
+                Ok(packs) => packs,
+                Err(e) => {
+                    log::warn!("Cannot parse world packs of {world:?}: {e}");
+                    Vec::new()
+                }
+            },
+            None => Vec::new(),
+        };
+        log::info!("world_packs parsed: {:#?}", world_packs);
+        self.update_index()?;
+        let packs = self.index.entries.iter().map(|entry| &entry.pack);
+        log::debug!("Installed packs: {:#?}", packs.clone().collect::<Vec<_>>());
+        // Keep using the same table even if the config changes meanwhile
+        let redirects = REDIRECTS.load_full();
+        let version_match = crate::platform::OPTS.lock().ignore_poison().version_match;
+        // Like 1.21.50.07, the last number is the build
+        let game_version: Option<Vec<u32>> = crate::platform::game_version_name()
+            .and_then(|name| name.split('.').map(|part| part.parse().ok()).collect());
+        let locale = crate::platform::get_game_language(
+            &self.active_packs_path.with_file_name("options.txt"),
+        )
+        .unwrap_or_else(|| "en_US".to_string());
         let mut final_paths = HashSet::new();
         // Explanation: we use .rev to reverse the iterator since this way we can avoid
         // some checks
-        for pack in global_packs.into_iter().rev() {
-            if let Some(vp) = find_valid_pack(&pack, &packs) {
+        // The first pack to add a file wins, so world packs go first
+        let all_packs = world_packs
+            .into_iter()
+            .rev()
+            .chain(global_packs.into_iter().rev());
+        for pack in all_packs {
+            if let Some(vp) = find_valid_pack(&pack, packs.clone(), version_match) {
+                let name = vp.display_name(&locale);
+                log::info!("Redirecting pack {name} from {:?}", vp.path);
+                log::debug!(
+                    "Pack {name} uses manifest format {}: {:?}",
+                    vp.format_version,
+                    vp.description.as_deref().unwrap_or_default()
+                );
+                if game_version
+                    .as_ref()
+                    .is_some_and(|version| !vp.supports_game(version))
+                {
+                    log::warn!(
+                        "Pack {name} needs game version {:?} or newer, it might not work",
+                        vp.min_engine_version
+                    );
+                }
                 // We pass the hashset directly to avoid useless allocations that get dropped instantly
-                vp.get_pack_files(pack.subpack, &mut final_paths);
+                vp.get_pack_files(
+                    pack.subpack,
+                    &redirects,
+                    &mut self.index.files,
+                    &mut final_paths,
+                );
             }
         }
         Ok(final_paths)
     }
-    fn get_installed_packs(&self) -> Result<Vec<ValidPack>, DataError> {
-        let pack_dirs = fs::read_dir(&self.resourcepacks_dir)?;
-        let mut packs = Vec::new();
-        for dir in pack_dirs.flatten() {
-            if !dir.file_type()?.is_dir() {
+    fn update_index(&mut self) -> Result<(), DataError> {
+        let mut old_entries: HashMap<PathBuf, IndexEntry> = self
+            .index
+            .entries
+            .drain(..)
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+        // World packs go last, so installed copies of a pack win over them
+        let world_packs = self
+            .world_dir
+            .as_ref()
+            .map(|world| world.join("resource_packs"));
+        let roots = self.pack_roots.iter().chain(&world_packs);
+        for root in roots {
+            // Not every root exists, development_resource_packs is often missing
+            if !root.is_dir() {
+                log::debug!("Skipping missing pack root: {root:?}");
                 continue;
             }
-            let manifest_path = match find_pack_folder(&dir.path()) {
-                Some(found) => found,
-                None => {
-                    log::warn!("Cannot find pack manifest for dir: {:?}", dir.path());
+            for dir in fs::read_dir(root)?.flatten() {
+                let path = dir.path();
+                let Ok(modified) = dir.metadata().and_then(|meta| meta.modified()) else {
                     continue;
+                };
+                if let Some(entry) = old_entries.remove(&path) {
+                    if entry.modified == modified
+                        && entry.manifest_modified == manifest_modified(&entry.pack)
+                    {
+                        self.index.entries.push(entry);
+                        continue;
+                    }
                 }
-            };
-            let validpack = match ValidPack::parse_manifest(manifest_path) {
-                Ok(pack) => pack,
-                Err(err) => {
-                    log::info!("Pack manifest parse failed: {err}");
+                // Anything we knew about it is outdated now
+                self.index
+                    .files
+                    .retain(|(pack_path, _), _| !pack_path.starts_with(&path));
+                // Broken packs are not kept, someone might be fixing them
+                let Some(pack) = parse_pack_entry(&dir)? else {
                     continue;
-                }
-            };
-            packs.push(validpack);
+                };
+                self.index.entries.push(IndexEntry {
+                    path,
+                    modified,
+                    manifest_modified: manifest_modified(&pack),
+                    pack,
+                });
+            }
+        }
+        // Whatever is left got removed
+        for path in old_entries.keys() {
+            archive::forget(path);
+            self.index
+                .files
+                .retain(|(pack_path, _), _| !pack_path.starts_with(path));
+        }
+        Ok(())
+    }
+}
+// Archives change as a whole so their own mtime covers this
+fn manifest_modified(pack: &ValidPack) -> Option<SystemTime> {
+    if pack.archive.is_some() {
+        return None;
+    }
+    let manifest = fs::metadata(pack.path.join("manifest.json")).ok()?;
+    manifest.modified().ok()
+}
+fn parse_pack_entry(dir: &fs::DirEntry) -> Result<Option<ValidPack>, DataError> {
+    let file_type = dir.file_type()?;
+    if file_type.is_file() && archive::is_archive(&dir.path()) {
+        log::debug!("Parsing pack archive {:?}", dir.path());
+        return match ValidPack::parse_archive(&dir.path()) {
+            Ok(pack) => Ok(Some(pack)),
+            Err(err) => {
+                log::info!("Pack archive {:?} parse failed: {err}", dir.path());
+                Ok(None)
+            }
+        };
+    }
+    if !file_type.is_dir() {
+        return Ok(None);
+    }
+    log::debug!("Parsing pack folder {:?}", dir.path());
+    let manifest_path = match find_pack_folder(&dir.path()) {
+        Some(found) => found,
+        None => {
+            log::warn!("Cannot find pack manifest for dir: {:?}", dir.path());
+            return Ok(None);
+        }
+    };
+    match ValidPack::parse_manifest(manifest_path) {
+        Ok(pack) => Ok(Some(pack)),
+        Err(err) => {
+            log::info!("Pack manifest parse failed: {err}");
+            Ok(None)
         }
-        Ok(packs)
     }
 }
 fn find_valid_pack<'a>(
     global_pack: &GlobalPack,
-    valid_packs: &'a [ValidPack],
+    valid_packs: impl Iterator<Item = &'a ValidPack> + Clone,
+    version_match: VersionMatch,
 ) -> Option<&'a ValidPack> {
-    for valid_pack in valid_packs {
-        if valid_pack.uuid.eq_ignore_ascii_case(&global_pack.pack_id)
-            && valid_pack.version == global_pack.version
-        {
-            return Some(valid_pack);
+    let mut candidates =
+        valid_packs.filter(|valid_pack| valid_pack.uuid.eq_ignore_ascii_case(&global_pack.pack_id));
+    if let Some(exact) = candidates
+        .clone()
+        .find(|valid_pack| valid_pack.version == global_pack.version)
+    {
+        return Some(exact);
+    }
+    let fallback = match version_match {
+        VersionMatch::Exact => None,
+        VersionMatch::SameMajor => candidates
+            .filter(|valid_pack| valid_pack.version.first() == global_pack.version.first())
+            .max_by(|a, b| a.version.cmp(&b.version)),
+        VersionMatch::Newest => candidates.max_by(|a, b| a.version.cmp(&b.version)),
+    };
+    match fallback {
+        Some(pack) => log::info!(
+            "Pack {} has no version {:?}, using {:?} from {:?}",
+            global_pack.pack_id,
+            global_pack.version,
+            pack.version,
+            pack.path
+        ),
+        None => log::warn!(
+            "Pack {} version {:?} is not installed",
+            global_pack.pack_id,
+            global_pack.version
+        ),
+    }
+    fallback
+}
+// How strict we are when the active version of a pack
+// is not the one that is installed, which happens when
+// a pack gets updated without being activated again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionMatch {
+    Exact,
+    // Newest installed version with the same major version
+    SameMajor,
+    Newest,
+}
+impl VersionMatch {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "exact" => Some(Self::Exact),
+            "same_major" => Some(Self::SameMajor),
+            "newest" => Some(Self::Newest),
+            _ => None,
         }
     }
-    None
 }
 
 // This is rare, but can happen
@@ -1,26 +1,29 @@
+use crate::redirect::REDIRECTS;
 use crate::SHADER_PATHS;
+use crate::{archive, autofix, material_cache};
 use crate::{mc_utils::ResourcePath, platform::OPTS};
 use libc::{off64_t, off_t};
-use materialbin::{
-    bgfx_shader::BgfxShader,
-    pass::{ShaderCodePlatform, ShaderStage},
-    CompiledMaterialDefinition, MinecraftVersion,
-};
-use memchr::memmem::Finder;
+use materialbin::MinecraftVersion;
 use std::{borrow::Cow, ptr::NonNull, sync::atomic::Ordering};
 //use ndk::asset::Asset;
 use crate::LockResultExt;
 use ndk::asset::{Asset, AssetManager};
-use ndk_sys::{AAsset, AAssetManager};
+use ndk_sys::{AAsset, AAssetDir, AAssetManager};
 use scroll::Pread;
 use std::{
-    collections::HashMap,
-    ffi::{CStr, OsStr},
+    collections::{HashMap, HashSet},
+    ffi::{CStr, CString, OsStr},
     fs::File,
-    io::{self, Cursor, Read, Seek},
-    os::unix::ffi::OsStrExt,
+    io::{self, Cursor, Read, Seek, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
     path::{Path, PathBuf},
-    sync::{atomic::AtomicBool, LazyLock, Mutex, OnceLock},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        LazyLock, Mutex, OnceLock,
+    },
 };
 // This makes me feel wrong... but all we will do is compare the pointer
 // and the struct will be used in a mutex so i guess this is safe??
@@ -29,8 +32,84 @@ struct AAssetPtr(*const ndk_sys::AAsset);
 unsafe impl Send for AAssetPtr {}
 
 // the assets we want to intercept access to
-static WANTED_ASSETS: LazyLock<Mutex<HashMap<AAssetPtr, CowFile>>> =
-    LazyLock::new(|| Mutex::new(HashMap::new()));
+static WANTED_ASSETS: LazyLock<AssetTable> = LazyLock::new(AssetTable::new);
+
+const ASSET_SHARDS: usize = 16;
+// Every AAsset call goes through here, even the ones for the thousands
+// of vanilla assets we dont touch, so those should not wait on a lock
+struct AssetTable {
+    // How many assets we have, when zero we can skip the locks entirely
+    count: AtomicUsize,
+    shards: [Mutex<HashMap<AAssetPtr, OurAsset>>; ASSET_SHARDS],
+}
+struct OurAsset {
+    file: CowFile,
+    // Set when the game has no asset with this name, then the
+    // handle is just the address of this and ndk must never see it
+    fake_handle: Option<Box<u8>>,
+}
+impl AssetTable {
+    fn new() -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            shards: std::array::from_fn(|_| Mutex::new(HashMap::new())),
+        }
+    }
+    fn shard(&self, aasset: *const AAsset) -> &Mutex<HashMap<AAssetPtr, OurAsset>> {
+        // The low bits are always the same because of alignment
+        let addr = aasset as usize;
+        &self.shards[((addr >> 4) ^ (addr >> 12)) % ASSET_SHARDS]
+    }
+    fn insert(&self, aasset: *const AAsset, asset: OurAsset) {
+        let mut shard = self.shard(aasset).lock().ignore_poison();
+        if shard.insert(AAssetPtr(aasset), asset).is_none() {
+            self.count.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+    fn remove(&self, aasset: *const AAsset) -> Option<OurAsset> {
+        if self.count.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        let removed = self
+            .shard(aasset)
+            .lock()
+            .ignore_poison()
+            .remove(&AAssetPtr(aasset));
+        if removed.is_some() {
+            self.count.fetch_sub(1, Ordering::AcqRel);
+        }
+        removed
+    }
+    // Run f on the asset if it is one of ours
+    fn with<R>(&self, aasset: *const AAsset, f: impl FnOnce(&mut CowFile) -> R) -> Option<R> {
+        if self.count.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        let mut shard = self.shard(aasset).lock().ignore_poison();
+        shard
+            .get_mut(&AAssetPtr(aasset))
+            .map(|asset| f(&mut asset.file))
+    }
+    // Forget an asset, returns true if it is a real one ndk has to close
+    fn close(&self, aasset: *const AAsset) -> bool {
+        match self.remove(aasset) {
+            // Dropping it frees the fake handle
+            Some(asset) => asset.fake_handle.is_none(),
+            None => true,
+        }
+    }
+    // For files only packs have, the handle is made up by us
+    fn insert_fake(&self, file: CowFile) -> *mut AAsset {
+        let fake_handle = Box::new(0u8);
+        let fake_aasset = (&*fake_handle as *const u8).cast_mut().cast::<AAsset>();
+        let asset = OurAsset {
+            file,
+            fake_handle: Some(fake_handle),
+        };
+        self.insert(fake_aasset, asset);
+        fake_aasset
+    }
+}
 pub(crate) unsafe fn asset_open(
     man: *mut AAssetManager,
     fname: *const libc::c_char,
@@ -50,49 +129,281 @@ pub(crate) unsafe fn asset_open(
         return aasset;
     };
     let stripped_path = c_path.strip_prefix("assets/").unwrap_or(c_path);
-    let replacement_list = [
-        ("gui/dist/hbui/", "hbui/"),
-        ("renderer/", "renderer/"),
-        ("resource_packs/vanilla/cameras", "vanilla_cameras/"),
-        ("skin_packs/persona", "custom_persona/"),
-    ];
-    for replacement in replacement_list {
-        if let Ok(path) = stripped_path.strip_prefix(replacement.0) {
-            let shader_paths = SHADER_PATHS.lock().unwrap();
-            // this will be used if the joined path fits
-            let mut bytes = [0; 128];
-            // this will be used if the joined path does not fit in bytes var
-            let mut planb = PathBuf::new();
-            // Try to avoid allocation
-            let path = opt_path_join(
-                &mut bytes,
-                Some(&mut planb),
-                &[Path::new(replacement.1), path],
-            );
-            let aah = ResourcePath::new_nameless(Cow::Borrowed(path));
-            // Try to get the file
-            let filepath = match shader_paths.get(&aah) {
-                Some(path) => path,
-                None => {
-                    log::info!("Cannot load file: {:?}", path);
-                    return aasset;
-                }
-            };
-            let file = match File::open(filepath.path()) {
-                Ok(file) => file,
-                Err(err) => {
-                    log::warn!("Cannot open file: {err}");
-                    return aasset;
-                }
-            };
-            let buffer = CowFile::File(file);
-
-            let mut wanted_lock = WANTED_ASSETS.lock().unwrap();
-            wanted_lock.insert(AAssetPtr(aasset), buffer);
+    // this will be used if the joined path fits
+    let mut bytes = [0; 128];
+    // this will be used if the joined path does not fit in bytes var
+    let mut planb = PathBuf::new();
+    let path = {
+        let redirects = REDIRECTS.load();
+        let Some((pack_prefix, rest)) = redirects.map(stripped_path) else {
+            return aasset;
+        };
+        // Try to avoid allocation
+        opt_path_join(&mut bytes, Some(&mut planb), &[pack_prefix, rest])
+    };
+    // A full clone, the guard from load() is not meant to be kept around
+    // while we read files and run the autofixer
+    let shader_paths = SHADER_PATHS.load_full();
+    let aah = ResourcePath::new_nameless(Cow::Borrowed(path));
+    // Try to get the file
+    let filepath = match shader_paths.get(&aah) {
+        Some(path) => path,
+        None => {
+            log::info!("Cannot load file: {:?}", path);
             return aasset;
         }
+    };
+    let mut file = match filepath.archive_entry() {
+        // Zipped packs have to be decompressed into memory
+        Some((archive, entry)) => match archive::read_entry(archive, entry) {
+            Ok(data) => CowFile::Buffer(Cursor::new(data)),
+            Err(err) => {
+                log::warn!("Cannot read file from pack archive: {err}");
+                return aasset;
+            }
+        },
+        None => match open_pack_file(filepath.path()) {
+            Ok(file) => file,
+            Err(err) => {
+                log::warn!("Cannot open file: {err}");
+                return aasset;
+            }
+        },
+    };
+    let buffer = if autofix::is_material(stripped_path) {
+        let mut data = Vec::new();
+        if let Err(e) = file.read_to_end(&mut data) {
+            log::warn!("Cannot read material: {e}");
+            return aasset;
+        }
+        if let Some(fixed) = autofix_material(aasset, &data) {
+            data = fixed;
+        }
+        CowFile::Buffer(Cursor::new(data))
+    } else {
+        file
+    };
+
+    if !aasset.is_null() {
+        let asset = OurAsset {
+            file: buffer,
+            fake_handle: None,
+        };
+        WANTED_ASSETS.insert(aasset, asset);
+        return aasset;
+    }
+    // Only the pack has this file, so we make up a handle for it
+    WANTED_ASSETS.insert_fake(buffer)
+}
+// Update a redirected material to the format of the vanilla one it replaces
+fn autofix_material(aasset: *mut AAsset, data: &[u8]) -> Option<Vec<u8>> {
+    // The vanilla material is the next best thing if the version probe failed
+    let target = crate::platform::game_version().or_else(|| unsafe { vanilla_version(aasset) });
+    let Some(target) = target else {
+        log::warn!("Cannot figure out game material version, not fixing");
+        return None;
+    };
+    // Other threads open materials too, dont keep them waiting on the conversion
+    let opts = OPTS.lock().ignore_poison().clone();
+    if !opts.autofix {
+        return None;
+    }
+    if !opts.material_cache {
+        return autofix::process_material(data, target, &opts);
+    }
+    material_cache::process_material(data, target, &opts)
+}
+
+// We read the real asset directly with ndk_sys so our hooks dont see it
+unsafe fn vanilla_version(aasset: *mut AAsset) -> Option<MinecraftVersion> {
+    if aasset.is_null() {
+        return None;
+    }
+    let len = ndk_sys::AAsset_getLength64(aasset);
+    let buffer = ndk_sys::AAsset_getBuffer(aasset);
+    if buffer.is_null() || len <= 0 {
+        return None;
+    }
+    let data = core::slice::from_raw_parts(buffer.cast::<u8>(), len as usize);
+    autofix::detect_version(data)
+}
+#[derive(PartialEq, Eq, Hash)]
+struct AAssetDirPtr(*const AAssetDir);
+unsafe impl Send for AAssetDirPtr {}
+
+// Listings of redirected folders, with the files only packs have
+static WANTED_DIRS: LazyLock<Mutex<HashMap<AAssetDirPtr, FakeDir>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+struct FakeDir {
+    // Names of pack files in this folder
+    extra: Vec<CString>,
+    next_extra: usize,
+    // What the real listing gave us, so we dont list those twice
+    seen: HashSet<CString>,
+}
+
+pub(crate) unsafe fn asset_open_dir(
+    man: *mut AAssetManager,
+    dirname: *const libc::c_char,
+) -> *mut AAssetDir {
+    let aasset_dir = ndk_sys::AAssetManager_openDir(man, dirname);
+    if aasset_dir.is_null() {
+        return aasset_dir;
+    }
+    let c_path = Path::new(OsStr::from_bytes(CStr::from_ptr(dirname).to_bytes()));
+    let stripped_path = c_path.strip_prefix("assets/").unwrap_or(c_path);
+    let pack_dir = {
+        let redirects = REDIRECTS.load();
+        let Some((pack_prefix, rest)) = redirects.map(stripped_path) else {
+            return aasset_dir;
+        };
+        pack_prefix.join(rest)
+    };
+    let shader_paths = SHADER_PATHS.load();
+    let extra: Vec<CString> = shader_paths
+        .iter()
+        .filter(|resource| resource.resource_name().parent() == Some(pack_dir.as_path()))
+        // Folders are in the set too, but listings only have files
+        .filter(|resource| resource.archive_entry().is_some() || resource.path().is_file())
+        .filter_map(|resource| resource.resource_name().file_name())
+        .filter_map(|name| CString::new(name.as_bytes()).ok())
+        .collect();
+    if extra.is_empty() {
+        return aasset_dir;
+    }
+    let fake_dir = FakeDir {
+        extra,
+        next_extra: 0,
+        seen: HashSet::new(),
+    };
+    let mut wanted_dirs = WANTED_DIRS.lock().ignore_poison();
+    wanted_dirs.insert(AAssetDirPtr(aasset_dir), fake_dir);
+    aasset_dir
+}
+
+pub(crate) unsafe fn asset_dir_next(aasset_dir: *mut AAssetDir) -> *const libc::c_char {
+    let mut wanted_dirs = WANTED_DIRS.lock().ignore_poison();
+    let Some(fake_dir) = wanted_dirs.get_mut(&AAssetDirPtr(aasset_dir)) else {
+        return ndk_sys::AAssetDir_getNextFileName(aasset_dir);
+    };
+    // The real files come first, then whatever packs add
+    let name = ndk_sys::AAssetDir_getNextFileName(aasset_dir);
+    if !name.is_null() {
+        fake_dir.seen.insert(CStr::from_ptr(name).to_owned());
+        return name;
+    }
+    while let Some(extra) = fake_dir.extra.get(fake_dir.next_extra) {
+        fake_dir.next_extra += 1;
+        if !fake_dir.seen.contains(extra) {
+            // Lives until the dir is closed
+            return extra.as_ptr();
+        }
+    }
+    std::ptr::null()
+}
+
+pub(crate) unsafe fn asset_dir_rewind(aasset_dir: *mut AAssetDir) {
+    let mut wanted_dirs = WANTED_DIRS.lock().ignore_poison();
+    if let Some(fake_dir) = wanted_dirs.get_mut(&AAssetDirPtr(aasset_dir)) {
+        fake_dir.next_extra = 0;
+        fake_dir.seen.clear();
+    }
+    ndk_sys::AAssetDir_rewind(aasset_dir);
+}
+
+pub(crate) unsafe fn asset_dir_close(aasset_dir: *mut AAssetDir) {
+    let mut wanted_dirs = WANTED_DIRS.lock().ignore_poison();
+    let _result = wanted_dirs.remove(&AAssetDirPtr(aasset_dir));
+    ndk_sys::AAssetDir_close(aasset_dir);
+}
+// Some data is read with plain libc calls instead of AAsset, like
+// assets the game extracted, so we redirect those paths too
+unsafe fn redirect_libc_path(path: *const libc::c_char) -> Option<CString> {
+    if path.is_null() {
+        return None;
+    }
+    let bytes = CStr::from_ptr(path).to_bytes();
+    // Only the folder the game extracts assets to, other paths can
+    // have an assets folder too, like a world or a pack
+    let rest = bytes.strip_prefix(super::extracted_assets_root()?)?;
+    // What asset_open would see for the same file
+    let asset_path = Path::new(OsStr::from_bytes(rest));
+    // These have to go through the autofixer, which only asset_open does
+    if autofix::is_material(asset_path) {
+        return None;
+    }
+    // This runs on every open and stat the game does, so
+    // bail out early when there is nothing to redirect
+    let shader_paths = SHADER_PATHS.load();
+    if shader_paths.is_empty() {
+        return None;
+    }
+    let pack_path = {
+        let redirects = REDIRECTS.load();
+        let (pack_prefix, rest) = redirects.map(asset_path)?;
+        pack_prefix.join(rest)
+    };
+    let resource = shader_paths.get(&ResourcePath::new_nameless(Cow::Borrowed(&pack_path)))?;
+    // Zipped packs have nothing on disk we could point to
+    if resource.archive_entry().is_some() {
+        return None;
+    }
+    log::info!("Redirecting libc access to {:?}", resource.path());
+    CString::new(resource.path().as_os_str().as_bytes()).ok()
+}
+
+pub(crate) unsafe fn fopen_hook(
+    path: *const libc::c_char,
+    mode: *const libc::c_char,
+) -> *mut libc::FILE {
+    // Packs should never get written to
+    let read_only = !mode.is_null() && {
+        let mode = CStr::from_ptr(mode).to_bytes();
+        mode.starts_with(b"r") && !mode.contains(&b'+')
+    };
+    if read_only {
+        if let Some(redirected) = redirect_libc_path(path) {
+            return libc::fopen(redirected.as_ptr(), mode);
+        }
+    }
+    libc::fopen(path, mode)
+}
+
+// open is variadic, but mode is always passed in a register so this works
+pub(crate) unsafe fn open_hook(
+    path: *const libc::c_char,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+) -> libc::c_int {
+    if flags & libc::O_ACCMODE == libc::O_RDONLY {
+        if let Some(redirected) = redirect_libc_path(path) {
+            return libc::open(redirected.as_ptr(), flags, mode);
+        }
+    }
+    libc::open(path, flags, mode)
+}
+
+pub(crate) unsafe fn openat_hook(
+    dirfd: libc::c_int,
+    path: *const libc::c_char,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+) -> libc::c_int {
+    // Pack paths are absolute, so dirfd does not matter for them
+    if flags & libc::O_ACCMODE == libc::O_RDONLY {
+        if let Some(redirected) = redirect_libc_path(path) {
+            return libc::openat(dirfd, redirected.as_ptr(), flags, mode);
+        }
+    }
+    libc::openat(dirfd, path, flags, mode)
+}
+
+pub(crate) unsafe fn stat_hook(path: *const libc::c_char, buf: *mut libc::stat) -> libc::c_int {
+    match redirect_libc_path(path) {
+        Some(redirected) => libc::stat(redirected.as_ptr(), buf),
+        None => libc::stat(path, buf),
     }
-    return aasset;
 }
 /// Join paths without allocating if possible, or
 /// if the joined path does not fit the buffer then just
@@ -127,24 +438,20 @@ pub(crate) unsafe fn asset_seek64(
     off: off64_t,
     whence: libc::c_int,
 ) -> off64_t {
-    let mut wanted_assets = WANTED_ASSETS.lock().unwrap();
-    let file = match wanted_assets.get_mut(&AAssetPtr(aasset)) {
-        Some(file) => file,
-        None => return ndk_sys::AAsset_seek64(aasset, off, whence),
-    };
-    seek_facade(off, whence, file) as off64_t
+    match WANTED_ASSETS.with(aasset, |file| seek_facade(off, whence, file)) {
+        Some(offset) => offset as off64_t,
+        None => ndk_sys::AAsset_seek64(aasset, off, whence),
+    }
 }
 
 pub(crate) unsafe fn asset_seek(aasset: *mut AAsset, off: off_t, whence: libc::c_int) -> off_t {
-    let mut wanted_assets = WANTED_ASSETS.lock().unwrap();
-    let file = match wanted_assets.get_mut(&AAssetPtr(aasset)) {
-        Some(file) => file,
-        None => return ndk_sys::AAsset_seek(aasset, off, whence),
-    };
     // This code can be very deadly on large files,
     // but since NO replacement should surpass u32 max we should be fine...
     // i dont even think a mcpack can exceed that
-    seek_facade(off.into(), whence, file) as off_t
+    match WANTED_ASSETS.with(aasset, |file| seek_facade(off.into(), whence, file)) {
+        Some(offset) => offset as off_t,
+        None => ndk_sys::AAsset_seek(aasset, off, whence),
+    }
 }
 
 pub(crate) unsafe fn asset_read(
@@ -152,73 +459,65 @@ pub(crate) unsafe fn asset_read(
     buf: *mut libc::c_void,
     count: libc::size_t,
 ) -> libc::c_int {
-    let mut wanted_assets = WANTED_ASSETS.lock().unwrap();
-    let file = match wanted_assets.get_mut(&AAssetPtr(aasset)) {
-        Some(file) => file,
-        None => return ndk_sys::AAsset_read(aasset, buf, count),
-    };
-    // Reuse buffer given by caller
-    let rs_buffer = core::slice::from_raw_parts_mut(buf as *mut u8, count);
-    let read_total = match file.read(rs_buffer) {
-        Ok(n) => n,
-        Err(e) => {
+    let result = WANTED_ASSETS.with(aasset, |file| {
+        // Reuse buffer given by caller
+        let rs_buffer = core::slice::from_raw_parts_mut(buf as *mut u8, count);
+        file.read(rs_buffer)
+    });
+    match result {
+        Some(Ok(read_total)) => read_total as libc::c_int,
+        Some(Err(e)) => {
             log::warn!("failed fake aaset read: {e}");
-            return -1 as libc::c_int;
+            -1 as libc::c_int
         }
-    };
-    read_total as libc::c_int
+        None => ndk_sys::AAsset_read(aasset, buf, count),
+    }
 }
 
 pub(crate) unsafe fn asset_length(aasset: *mut AAsset) -> off_t {
-    let wanted_assets = WANTED_ASSETS.lock().unwrap();
-    let file = match wanted_assets.get(&AAssetPtr(aasset)) {
-        Some(file) => file,
-        None => return ndk_sys::AAsset_getLength(aasset),
-    };
-    file.len().unwrap() as off_t
+    match WANTED_ASSETS.with(aasset, |file| file.len().unwrap()) {
+        Some(len) => len as off_t,
+        None => ndk_sys::AAsset_getLength(aasset),
+    }
 }
 
 pub(crate) unsafe fn asset_length64(aasset: *mut AAsset) -> off64_t {
-    let wanted_assets = WANTED_ASSETS.lock().unwrap();
-    let file = match wanted_assets.get(&AAssetPtr(aasset)) {
-        Some(file) => file,
-        None => return ndk_sys::AAsset_getLength64(aasset),
-    };
-    file.len().unwrap() as off64_t
+    match WANTED_ASSETS.with(aasset, |file| file.len().unwrap()) {
+        Some(len) => len as off64_t,
+        None => ndk_sys::AAsset_getLength64(aasset),
+    }
 }
 
 pub(crate) unsafe fn asset_remaining(aasset: *mut AAsset) -> off_t {
-    let mut wanted_assets = WANTED_ASSETS.lock().unwrap();
-    let file = match wanted_assets.get_mut(&AAssetPtr(aasset)) {
-        Some(file) => file,
-        None => return ndk_sys::AAsset_getRemainingLength(aasset),
-    };
-    file.rem().unwrap() as off_t
+    match WANTED_ASSETS.with(aasset, |file| file.rem().unwrap()) {
+        Some(rem) => rem as off_t,
+        None => ndk_sys::AAsset_getRemainingLength(aasset),
+    }
 }
 
 pub(crate) unsafe fn asset_remaining64(aasset: *mut AAsset) -> off64_t {
-    let mut wanted_assets = WANTED_ASSETS.lock().unwrap();
-    let file = match wanted_assets.get_mut(&AAssetPtr(aasset)) {
-        Some(file) => file,
-        None => return ndk_sys::AAsset_getRemainingLength64(aasset),
-    };
-    file.rem().unwrap() as off64_t
+    match WANTED_ASSETS.with(aasset, |file| file.rem().unwrap()) {
+        Some(rem) => rem as off64_t,
+        None => ndk_sys::AAsset_getRemainingLength64(aasset),
+    }
 }
 
 pub(crate) unsafe fn asset_close(aasset: *mut AAsset) {
-    let mut wanted_assets = WANTED_ASSETS.lock().unwrap();
-    let _result = wanted_assets.remove(&AAssetPtr(aasset));
-    ndk_sys::AAsset_close(aasset);
+    if WANTED_ASSETS.close(aasset) {
+        ndk_sys::AAsset_close(aasset);
+    }
 }
 
 pub(crate) unsafe fn asset_get_buffer(aasset: *mut AAsset) -> *const libc::c_void {
-    let mut wanted_assets = WANTED_ASSETS.lock().unwrap();
-    let file = match wanted_assets.get_mut(&AAssetPtr(aasset)) {
-        Some(file) => file,
-        None => return ndk_sys::AAsset_getBuffer(aasset),
-    };
-    // Lets hope this does not go boom boom
-    file.raw_buffer().unwrap().cast()
+    // The buffer belongs to the asset, so it stays valid until AAsset_close
+    match WANTED_ASSETS.with(aasset, |file| file.raw_buffer()) {
+        Some(Ok(buffer)) => buffer.cast(),
+        Some(Err(e)) => {
+            log::warn!("failed fake aasset getBuffer: {e}");
+            std::ptr::null()
+        }
+        None => ndk_sys::AAsset_getBuffer(aasset),
+    }
 }
 
 pub(crate) unsafe fn asset_fd_dummy(
@@ -226,13 +525,19 @@ pub(crate) unsafe fn asset_fd_dummy(
     out_start: *mut off_t,
     out_len: *mut off_t,
 ) -> libc::c_int {
-    let wanted_assets = WANTED_ASSETS.lock().unwrap();
-    match wanted_assets.get(&AAssetPtr(aasset)) {
-        Some(_) => {
-            log::error!("WE GOT BUSTED NOOO");
+    let Some(result) = WANTED_ASSETS.with(aasset, fake_fd) else {
+        return ndk_sys::AAsset_openFileDescriptor(aasset, out_start, out_len);
+    };
+    match result {
+        Ok((fd, len)) => {
+            *out_start = 0;
+            *out_len = len as off_t;
+            fd
+        }
+        Err(e) => {
+            log::error!("Cannot make fd for fake aasset: {e}");
             -1
         }
-        None => ndk_sys::AAsset_openFileDescriptor(aasset, out_start, out_len),
     }
 }
 
@@ -241,20 +546,31 @@ pub(crate) unsafe fn asset_fd_dummy64(
     out_start: *mut off64_t,
     out_len: *mut off64_t,
 ) -> libc::c_int {
-    let wanted_assets = WANTED_ASSETS.lock().unwrap();
-    match wanted_assets.get(&AAssetPtr(aasset)) {
-        Some(_) => {
-            log::error!("WE GOT BUSTED NOOO");
+    let Some(result) = WANTED_ASSETS.with(aasset, fake_fd) else {
+        return ndk_sys::AAsset_openFileDescriptor64(aasset, out_start, out_len);
+    };
+    match result {
+        Ok((fd, len)) => {
+            *out_start = 0;
+            *out_len = len as off64_t;
+            fd
+        }
+        Err(e) => {
+            log::error!("Cannot make fd for fake aasset: {e}");
             -1
         }
-        None => ndk_sys::AAsset_openFileDescriptor64(aasset, out_start, out_len),
     }
 }
 
+// Our assets are never inside an apk, so the fd always starts at 0
+fn fake_fd(file: &mut CowFile) -> Result<(libc::c_int, u64), io::Error> {
+    let len = file.len()?;
+    Ok((file.open_fd()?.into_raw_fd(), len))
+}
+
 pub(crate) unsafe fn asset_is_alloc(aasset: *mut AAsset) -> libc::c_int {
-    let wanted_assets = WANTED_ASSETS.lock().unwrap();
-    match wanted_assets.get(&AAssetPtr(aasset)) {
-        Some(_) => false as libc::c_int,
+    match WANTED_ASSETS.with(aasset, |file| file.is_allocated()) {
+        Some(allocated) => allocated as libc::c_int,
         None => ndk_sys::AAsset_isAllocated(aasset),
     }
 }
@@ -298,12 +614,15 @@ fn seek_facade(offset: i64, whence: libc::c_int, file: &mut CowFile) -> i64 {
 enum CowFile {
     File(File),
     Buffer(Cursor<Vec<u8>>),
+    // The file is kept for AAsset_openFileDescriptor
+    Mapped(Cursor<Mapping>, File),
 }
 impl Read for CowFile {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self {
             Self::File(file) => file.read(buf),
             Self::Buffer(cursor) => cursor.read(buf),
+            Self::Mapped(cursor, _) => cursor.read(buf),
         }
     }
 }
@@ -312,7 +631,78 @@ impl Seek for CowFile {
         match self {
             Self::File(file) => file.seek(pos),
             Self::Buffer(cursor) => cursor.seek(pos),
+            Self::Mapped(cursor, _) => cursor.seek(pos),
+        }
+    }
+}
+// Files up to this size are copied into memory
+const MAX_COPIED_LEN: u64 = 1024 * 1024;
+// Reading a mapping past the end of a file that got truncated under it
+// is a SIGBUS, and the game keeps some assets open for a long time,
+// so we only map big files from packs nobody should be editing
+fn open_pack_file(path: &Path) -> io::Result<CowFile> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len <= MAX_COPIED_LEN {
+        let mut data = Vec::with_capacity(len as usize);
+        file.read_to_end(&mut data)?;
+        return Ok(CowFile::Buffer(Cursor::new(data)));
+    }
+    // Development packs get edited in place all the time
+    let is_development = path
+        .components()
+        .any(|component| component.as_os_str() == "development_resource_packs");
+    if is_development {
+        return Ok(CowFile::File(file));
+    }
+    // Mapping can fail on odd filesystems
+    match Mapping::new(&file) {
+        Ok(mapping) => Ok(CowFile::Mapped(Cursor::new(mapping), file)),
+        Err(_) => Ok(CowFile::File(file)),
+    }
+}
+// A read only mmap of a whole file, same as what
+// the asset manager does for uncompressed assets
+struct Mapping {
+    ptr: NonNull<libc::c_void>,
+    len: usize,
+}
+// It is only ever read, so moving it between threads is fine
+unsafe impl Send for Mapping {}
+impl Mapping {
+    fn new(file: &File) -> io::Result<Self> {
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(io::Error::other("cannot map empty file"));
         }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // The mapping stays valid after the fd is closed
+        Ok(Self {
+            ptr: NonNull::new(ptr).unwrap(),
+            len,
+        })
+    }
+}
+impl AsRef<[u8]> for Mapping {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr().cast(), self.len) }
+    }
+}
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr(), self.len) };
     }
 }
 impl CowFile {
@@ -320,24 +710,210 @@ impl CowFile {
         Ok(match self {
             Self::File(file) => file.metadata()?.len(),
             Self::Buffer(cursor) => cursor.get_ref().len() as _,
+            Self::Mapped(cursor, _) => cursor.get_ref().len as _,
         })
     }
     fn rem(&mut self) -> Result<u64, io::Error> {
         Ok(self.len()? - self.stream_position()?)
     }
-    fn raw_buffer(&mut self) -> Result<*mut u8, io::Error> {
-        let len = self.len()? as usize;
-        let mut vec = match self {
-            Self::File(file) => {
-                let mut vec = Vec::with_capacity(len);
-                file.read_to_end(&mut vec)?;
-                vec
+    // Files get read into memory once, and then we keep
+    // serving from there so the pointer stays valid
+    fn raw_buffer(&mut self) -> Result<*const u8, io::Error> {
+        if let Self::File(file) = self {
+            let len = file.metadata()?.len() as usize;
+            // Reads after getBuffer have to continue from the same place
+            let position = file.stream_position()?;
+            let mut vec = Vec::with_capacity(len);
+            file.rewind()?;
+            file.read_to_end(&mut vec)?;
+            let mut cursor = Cursor::new(vec);
+            cursor.set_position(position);
+            *self = Self::Buffer(cursor);
+        }
+        match self {
+            Self::Buffer(cursor) => Ok(cursor.get_ref().as_ptr()),
+            Self::Mapped(cursor, _) => Ok(cursor.get_ref().ptr.as_ptr().cast()),
+            Self::File(_) => unreachable!(),
+        }
+    }
+    // Same meaning as AAsset_isAllocated, if the data lives in ordinary memory
+    fn is_allocated(&self) -> bool {
+        match self {
+            Self::Mapped(..) => false,
+            // Files get read into memory on getBuffer
+            Self::File(_) | Self::Buffer(_) => true,
+        }
+    }
+    // A new fd with the whole asset in it, the caller has to close it
+    fn open_fd(&self) -> Result<OwnedFd, io::Error> {
+        match self {
+            // A dup would share one offset between everyone
+            // we give it to and our own reads, so open it again
+            Self::File(file) | Self::Mapped(_, file) => {
+                let file = File::open(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+                Ok(file.into())
             }
-            Self::Buffer(cursor) => cursor.get_ref().clone(),
-        };
+            Self::Buffer(cursor) => {
+                let name = c"mcbe_r_asset";
+                let fd = unsafe {
+                    libc::syscall(libc::SYS_memfd_create, name.as_ptr(), libc::MFD_CLOEXEC)
+                };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let mut file = unsafe { File::from_raw_fd(fd as RawFd) };
+                file.write_all(cursor.get_ref())?;
+                // Callers expect to start reading at the start offset we give them
+                file.rewind()?;
+                Ok(file.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::SeekFrom;
+
+    const DATA: &[u8] = b"0123456789";
+
+    // A file with DATA in it, removed when dropped
+    struct TempFile(PathBuf);
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("mcbe_r_{}_{name}", std::process::id()));
+            File::create(&path).unwrap().write_all(DATA).unwrap();
+            Self(path)
+        }
+        fn open(&self) -> File {
+            File::open(&self.0).unwrap()
+        }
+    }
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn read_n(file: &mut CowFile, n: usize) -> Vec<u8> {
+        let mut buf = vec![0; n];
+        file.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn get_buffer_keeps_read_position() {
+        let temp = TempFile::new("position");
+        let mut file = CowFile::File(temp.open());
+        assert_eq!(read_n(&mut file, 3), b"012");
+        let ptr = file.raw_buffer().unwrap();
+        assert!(matches!(file, CowFile::Buffer(_)));
+        assert_eq!(unsafe { std::slice::from_raw_parts(ptr, DATA.len()) }, DATA);
+        assert_eq!(read_n(&mut file, 3), b"345");
+    }
+
+    #[test]
+    fn get_buffer_pointer_is_stable() {
+        let temp = TempFile::new("stable");
+        let mapped = CowFile::Mapped(
+            Cursor::new(Mapping::new(&temp.open()).unwrap()),
+            temp.open(),
+        );
+        for mut file in [CowFile::File(temp.open()), mapped] {
+            let ptr = file.raw_buffer().unwrap();
+            read_n(&mut file, 4);
+            file.seek(SeekFrom::End(-2)).unwrap();
+            read_n(&mut file, 2);
+            file.seek(SeekFrom::Start(1)).unwrap();
+            assert_eq!(file.raw_buffer().unwrap(), ptr);
+            assert_eq!(unsafe { std::slice::from_raw_parts(ptr, DATA.len()) }, DATA);
+        }
+    }
+
+    // What the hooks do for a file only a pack has, without
+    // the ndk fallbacks that only exist on android
+    #[test]
+    fn fake_asset_lifecycle() {
+        let temp = TempFile::new("lifecycle");
+        let aasset = WANTED_ASSETS.insert_fake(CowFile::File(temp.open()));
+        let read = |n| WANTED_ASSETS.with(aasset, |file| read_n(file, n)).unwrap();
+        assert_eq!(read(3), b"012");
+        let ptr = WANTED_ASSETS
+            .with(aasset, |file| file.raw_buffer())
+            .unwrap()
+            .unwrap();
+        assert_eq!(unsafe { std::slice::from_raw_parts(ptr, DATA.len()) }, DATA);
+        // Reading goes on where it was before getBuffer
+        assert_eq!(read(3), b"345");
+        let rem = WANTED_ASSETS.with(aasset, |file| file.rem().unwrap());
+        assert_eq!(rem, Some(4));
+        let offset = WANTED_ASSETS.with(aasset, |file| seek_facade(0, libc::SEEK_SET, file));
+        assert_eq!(offset, Some(0));
+        let again = WANTED_ASSETS.with(aasset, |file| file.raw_buffer().unwrap());
+        assert_eq!(again, Some(ptr));
+        assert_eq!(
+            WANTED_ASSETS.with(aasset, |file| file.is_allocated()),
+            Some(true)
+        );
+        // The handle was made up, so ndk must never see it
+        assert!(!WANTED_ASSETS.close(aasset));
+        assert!(WANTED_ASSETS.with(aasset, |_| ()).is_none());
+    }
+
+    #[test]
+    fn asset_table_tracks_count() {
+        let table = AssetTable::new();
+        let handles: Vec<Box<u8>> = (0..20).map(|_| Box::new(0)).collect();
+        let aassets: Vec<*const AAsset> = handles
+            .iter()
+            .map(|handle| (&**handle as *const u8).cast())
+            .collect();
+        // Nothing in it, so nothing is ours
+        assert!(table.with(aassets[0], |_| ()).is_none());
+        for aasset in &aassets {
+            let asset = OurAsset {
+                file: CowFile::Buffer(Cursor::new(DATA.to_vec())),
+                fake_handle: None,
+            };
+            table.insert(*aasset, asset);
+        }
+        assert_eq!(table.count.load(Ordering::Acquire), aassets.len());
+        let first = table.with(aassets[5], |file| read_n(file, 2));
+        assert_eq!(first.as_deref(), Some(&b"01"[..]));
+        // Every asset has its own position
+        let second = table.with(aassets[6], |file| read_n(file, 2));
+        assert_eq!(second.as_deref(), Some(&b"01"[..]));
+        for aasset in &aassets {
+            assert!(table.remove(*aasset).is_some());
+            assert!(table.remove(*aasset).is_none());
+        }
+        // Real assets still have to be closed by ndk
+        assert!(table.close(aassets[0]));
+        assert_eq!(table.count.load(Ordering::Acquire), 0);
+        assert!(table.with(aassets[5], |_| ()).is_none());
+    }
+
+    #[test]
+    fn seek_facade_whences() {
+        let mut file = CowFile::Buffer(Cursor::new(DATA.to_vec()));
+        assert_eq!(file.len().unwrap(), 10);
+        assert_eq!(seek_facade(4, libc::SEEK_SET, &mut file), 4);
+        assert_eq!(file.rem().unwrap(), 6);
+        assert_eq!(seek_facade(2, libc::SEEK_CUR, &mut file), 6);
+        assert_eq!(seek_facade(-3, libc::SEEK_CUR, &mut file), 3);
+        assert_eq!(seek_facade(-1, libc::SEEK_END, &mut file), 9);
+        assert_eq!(file.rem().unwrap(), 1);
+        assert_eq!(seek_facade(0, 42, &mut file), -1);
+    }
 
-        let ptr = vec.as_mut_ptr();
-        //        std::mem::forget(vec);
-        Ok(ptr)
+    #[test]
+    fn seek_facade_rejects_negative_start() {
+        let mut file = CowFile::Buffer(Cursor::new(DATA.to_vec()));
+        assert_eq!(seek_facade(5, libc::SEEK_SET, &mut file), 5);
+        assert_eq!(seek_facade(-1, libc::SEEK_SET, &mut file), -1);
+        // A failed seek does not move the file
+        assert_eq!(file.stream_position().unwrap(), 5);
+        assert_eq!(file.rem().unwrap(), 5);
     }
 }
@@ -2,15 +2,13 @@ mod hooks;
 pub mod storage;
 //use crate::hooking::{setup_hook, unsetup_hook};
 
-use self::storage::{parse_storage_location, StorageLocation};
+use self::storage::{parse_game_language, parse_storage_location, StorageLocation};
 use super::errors::HookError;
-use libc::{c_char, c_void, fopen, FILE};
+use libc::c_void;
 use libloading::{Library, Symbol};
 // use openvfs::FileProvider;
 use plt_rs::{collect_modules, DynamicLibrary};
 
-use std::ffi::{CStr, OsStr};
-use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::sync::OnceLock;
 use std::time::Duration;
@@ -21,20 +19,34 @@ struct JniPaths {
     external_path: String,
 }
 
+#[derive(Debug)]
+struct GameVersion {
+    name: String,
+    format: Option<MinecraftVersion>,
+}
+
 type IsEduFn = unsafe extern "C" fn(jni::JNIEnv, jni::objects::JObject);
 static JNI_PATHS: OnceLock<JniPaths> = OnceLock::new();
+static GAME_VERSION: OnceLock<GameVersion> = OnceLock::new();
+// Where the game extracts assets it later reads with libc calls,
+// with a trailing slash so it can be used as a prefix
+static EXTRACTED_ASSETS: OnceLock<Vec<u8>> = OnceLock::new();
 
 bhook::hook_fn! {
 fn edu_hook(env: jni::JNIEnv, thiz: jni::objects::JObject) -> () = {
-    use crate::platform::android::{get_string_from_fn, JNI_PATHS, JniPaths};
+    use crate::platform::android::{
+        get_string_from_fn, setup_extracted_assets, setup_game_version, JNI_PATHS, JniPaths,
+    };
     let mut env = env;
     let external_path = get_string_from_fn(&mut env, &thiz, "getExternalStoragePath");
     let internal_path = get_string_from_fn(&mut env, &thiz, "getInternalStoragePath");
+    setup_extracted_assets(&internal_path);
     let paths = JniPaths {
         internal_path,
         external_path,
     };
     JNI_PATHS.set(paths).unwrap();
+    setup_game_version(&mut env, &thiz);
     crate::common::SHOULD_STOP.store(true, std::sync::atomic::Ordering::Release);
     crate::start_thread();
     self_disable()
@@ -53,6 +65,71 @@ fn get_string_from_fn(
     let path_str = env.get_string(jstring.as_ref().into()).unwrap();
     path_str.to_str().unwrap().to_owned()
 }
+// Read the versionName of the apk we are running in
+fn get_version_name(
+    env: &mut jni::JNIEnv,
+    activity: &jni::objects::JObject,
+) -> jni::errors::Result<String> {
+    let package_name = env
+        .call_method(activity, "getPackageName", "()Ljava/lang/String;", &[])?
+        .l()?;
+    let package_manager = env
+        .call_method(
+            activity,
+            "getPackageManager",
+            "()Landroid/content/pm/PackageManager;",
+            &[],
+        )?
+        .l()?;
+    let package_info = env
+        .call_method(
+            &package_manager,
+            "getPackageInfo",
+            "(Ljava/lang/String;I)Landroid/content/pm/PackageInfo;",
+            &[(&package_name).into(), 0.into()],
+        )?
+        .l()?;
+    let version_name = env
+        .get_field(&package_info, "versionName", "Ljava/lang/String;")?
+        .l()?;
+    let version_name = env.get_string(version_name.as_ref().into())?;
+    Ok(version_name.into())
+}
+fn setup_game_version(env: &mut jni::JNIEnv, activity: &jni::objects::JObject) {
+    let name = match get_version_name(env, activity) {
+        Ok(name) => name,
+        Err(e) => {
+            log::warn!("Cannot get minecraft version: {e}");
+            // Dont leave a pending java exception behind
+            let _ = env.exception_clear();
+            return;
+        }
+    };
+    let format = crate::autofix::format_for_game_version(&name);
+    match format {
+        Some(format) => log::info!("Minecraft version: {name}, material format: {format}"),
+        None => log::warn!("Minecraft version: {name}, material format is unknown"),
+    }
+    let _ = GAME_VERSION.set(GameVersion { name, format });
+}
+fn setup_extracted_assets(internal_path: &str) {
+    let root = format!("{}/assets/", internal_path.trim_end_matches('/'));
+    log::info!("Extracted assets root: {root}");
+    let _ = EXTRACTED_ASSETS.set(root.into_bytes());
+}
+/// The folder the game extracts assets to, None until java told us
+pub fn extracted_assets_root() -> Option<&'static [u8]> {
+    EXTRACTED_ASSETS.get().map(Vec::as_slice)
+}
+/// The material format of the game we are running in,
+/// None if we dont know it (yet)
+pub fn game_version() -> Option<MinecraftVersion> {
+    GAME_VERSION.get()?.format
+}
+/// The raw versionName of the game, like "1.21.60.10"
+pub fn game_version_name() -> Option<&'static str> {
+    GAME_VERSION.get().map(|version| version.name.as_str())
+}
 pub fn get_storage_location(options_path: &Path) -> Option<StorageLocation> {
     let int = match parse_storage_location(options_path) {
         Ok(location) => location,
@@ -63,11 +140,21 @@ pub fn get_storage_location(options_path: &Path) -> Option<StorageLocation> {
     };
     StorageLocation::from_i8(int)
 }
+// The language the game is set to, for pack texts
+pub fn get_game_language(options_path: &Path) -> Option<String> {
+    match parse_game_language(options_path) {
+        Ok(language) => Some(language),
+        Err(e) => {
+            log::info!("Cant parse game language: {e}");
+            None
+        }
+    }
+}
 
+// What we log when the config does not say otherwise
+pub const DEFAULT_LOG_LEVEL: log::LevelFilter = log::LevelFilter::Trace;
 pub fn setup_logging() {
-    android_logger::init_once(
-        android_logger::Config::default().with_max_level(log::LevelFilter::Trace),
-    );
+    android_logger::init_once(android_logger::Config::default().with_max_level(DEFAULT_LOG_LEVEL));
 }
 // Get the full path for a storage location
 pub fn get_storage_path(location: StorageLocation) -> std::path::PathBuf {
@@ -89,16 +176,17 @@ pub fn get_storage_path(location: StorageLocation) -> std::path::PathBuf {
     result.into()
 }
 
+/// Where we keep our own files, this does not wait for
+/// java like get_storage_path so its fine to call from hooks
+pub fn get_data_dir() -> Option<std::path::PathBuf> {
+    let paths = JNI_PATHS.get()?;
+    Some(Path::new(&paths.internal_path).join("mcbe_r"))
+}
+
 // Get app directory for the current platform
 pub fn get_path() -> std::path::PathBuf {
     get_storage_path(StorageLocation::Internal)
 }
-pub unsafe fn fake_fopen(name: *const c_char, mode: *const c_char) -> *mut FILE {
-    let cname = CStr::from_ptr(name);
-    let osstr = OsStr::from_bytes(cname.to_bytes());
-    let guh = Path::new(osstr);
-    fopen(name, mode)
-}
 // Setup asset hooks
 pub fn setup_hooks() -> Result<(), HookError> {
     const LIBNAME: &str = "libminecraftpe.so";
@@ -143,7 +231,17 @@ pub fn setup_hooks() -> Result<(), HookError> {
             ),
             ("AAsset_getBuffer", hooks::asset_get_buffer as *const _),
             ("AAsset_isAllocated", hooks::asset_is_alloc as *const _),
-            // ("fopen", open_hook as *const _),
+            ("AAssetManager_openDir", hooks::asset_open_dir as *const _),
+            (
+                "AAssetDir_getNextFileName",
+                hooks::asset_dir_next as *const _,
+            ),
+            ("AAssetDir_rewind", hooks::asset_dir_rewind as *const _),
+            ("AAssetDir_close", hooks::asset_dir_close as *const _),
+            ("fopen", hooks::fopen_hook as *const _),
+            ("open", hooks::open_hook as *const _),
+            ("openat", hooks::openat_hook as *const _),
+            ("stat", hooks::stat_hook as *const _),
         ],
     )?;
     log::info!("Finished Hooking");
@@ -208,27 +306,70 @@ fn replace_plt_function(
 }
 use std::sync::{LazyLock, Mutex};
 
+use crate::mc_utils::VersionMatch;
 use jni::{
     objects::{AsJArrayRaw, JObject, JObjectArray, JPrimitiveArray, JString},
     sys::{jboolean, JNI_TRUE},
     JNIEnv,
 };
 use materialbin::{MinecraftVersion, ALL_VERSIONS};
+#[derive(Clone)]
 pub struct Options {
+    pub autofix: bool,
+    pub material_cache: bool,
+    pub preconvert_materials: bool,
     pub handle_lightmaps: bool,
     pub handle_texturelods: bool,
     pub autofixer_versions: Vec<MinecraftVersion>,
+    // Folders in games/com.mojang to look for packs in, first one wins
+    pub pack_roots: Vec<String>,
+    pub version_match: VersionMatch,
 }
 impl Default for Options {
     fn default() -> Self {
         Self {
+            autofix: true,
+            material_cache: true,
+            preconvert_materials: true,
             handle_lightmaps: true,
             handle_texturelods: true,
             autofixer_versions: ALL_VERSIONS.to_vec(),
+            pack_roots: vec![
+                "resource_packs".to_string(),
+                "development_resource_packs".to_string(),
+            ],
+            version_match: VersionMatch::SameMajor,
         }
     }
 }
 pub static OPTS: LazyLock<Mutex<Options>> = LazyLock::new(|| Mutex::new(Options::default()));
+
+// What the launcher set through jni, kept apart so reloading
+// the config can rebuild OPTS without losing them
+#[derive(Default)]
+pub struct JniOptions {
+    autofixer_versions: Option<Vec<MinecraftVersion>>,
+    handle_lightmaps: Option<bool>,
+    handle_texturelods: Option<bool>,
+}
+impl JniOptions {
+    pub fn apply(&self, opts: &mut Options) {
+        if let Some(versions) = &self.autofixer_versions {
+            opts.autofixer_versions = versions.clone();
+        }
+        if let Some(on) = self.handle_lightmaps {
+            opts.handle_lightmaps = on;
+        }
+        if let Some(on) = self.handle_texturelods {
+            opts.handle_texturelods = on;
+        }
+    }
+}
+pub static JNI_OPTS: Mutex<JniOptions> = Mutex::new(JniOptions {
+    autofixer_versions: None,
+    handle_lightmaps: None,
+    handle_texturelods: None,
+});
 #[no_mangle]
 extern "C" fn Java_io_bambosan_mbloader_launcherUtils_LibBindings_setAutofixVersions(
     mut env: JNIEnv,
@@ -243,9 +384,10 @@ extern "C" fn Java_io_bambosan_mbloader_launcherUtils_LibBindings_setAutofixVers
         let sus = env.get_string(&string).unwrap();
         rs_versions.push(version_from_string(sus.to_str().unwrap()).unwrap());
     }
+    JNI_OPTS.lock().unwrap().autofixer_versions = Some(rs_versions.clone());
     OPTS.lock().unwrap().autofixer_versions = rs_versions;
 }
-fn version_from_string(string: &str) -> Option<MinecraftVersion> {
+pub fn version_from_string(string: &str) -> Option<MinecraftVersion> {
     let mcversion = match string {
         "v1.18.30" => MinecraftVersion::V1_18_30,
         "v1.19.60" => MinecraftVersion::V1_19_60,
@@ -263,6 +405,7 @@ extern "C" fn Java_io_bambosan_mbloader_launcherUtils_LibBindings_setLightmapAut
     _thiz: JObject,
     on: jboolean,
 ) {
+    JNI_OPTS.lock().unwrap().handle_lightmaps = Some(on == JNI_TRUE);
     OPTS.lock().unwrap().handle_lightmaps = on == JNI_TRUE;
 }
 #[no_mangle]
@@ -271,5 +414,6 @@ extern "C" fn Java_io_bambosan_mbloader_launcherUtils_LibBindings_setTextureLodA
     _thiz: JObject,
     on: jboolean,
 ) {
+    JNI_OPTS.lock().unwrap().handle_texturelods = Some(on == JNI_TRUE);
     OPTS.lock().unwrap().handle_texturelods = on == JNI_TRUE;
 }
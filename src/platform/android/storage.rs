@@ -3,15 +3,6 @@ use std::{
     io::{BufRead, BufReader},
     path::Path,
 };
-macro_rules! from_error {
-    ($dis:ident, $errorType:ty, $targetError:ty) => {
-        impl From<$errorType> for $targetError {
-            fn from(value: $errorType) -> $targetError {
-                <$targetError>::$dis(value)
-            }
-        }
-    };
-}
 
 #[derive(Debug)]
 pub enum OptionsError {
@@ -48,13 +39,23 @@ impl StorageLocation {
     }
 }
 pub fn parse_storage_location(opt_path: &Path) -> Result<i8, OptionsError> {
+    let value = find_option(opt_path, "dvce_filestoragelocation")?;
+    Ok(value.parse::<i8>()?)
+}
+// Locale code like en_US
+pub fn parse_game_language(opt_path: &Path) -> Result<String, OptionsError> {
+    find_option(opt_path, "game_language")
+}
+fn find_option(opt_path: &Path, wanted: &str) -> Result<String, OptionsError> {
     let file = File::open(opt_path)?;
     let reader = BufReader::new(file);
     for line in reader.lines() {
         let line = line?;
-        let (key, value) = line.split_once(':').unwrap();
-        if key == "dvce_filestoragelocation" {
-            return Ok(value.parse::<i8>()?);
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        if key == wanted {
+            return Ok(value.to_string());
         }
     }
     Err(OptionsError::NotFound)
@@ -1,6 +1,7 @@
-#[cfg(not(any(target_os = "android", target_os = "ios")))]
+// Tests only need the logic that does not touch the game, so they run on the host
+#[cfg(not(any(target_os = "android", target_os = "ios", test)))]
 pub mod none;
-#[cfg(not(any(target_os = "android", target_os = "ios")))]
+#[cfg(not(any(target_os = "android", target_os = "ios", test)))]
 pub use none::*;
 
 pub mod android;
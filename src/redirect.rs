@@ -0,0 +1,154 @@
+use arc_swap::ArcSwap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+// Where the game looks for an asset, and where a pack can replace it
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    pub game_prefix: PathBuf,
+    pub pack_prefix: PathBuf,
+}
+
+// Every asset tree we redirect, shared by asset_open and
+// the pack walker so both always agree on what is interesting
+#[derive(Debug, Clone)]
+pub struct RedirectTable {
+    redirects: Vec<Redirect>,
+    overrides: AssetOverrides,
+}
+
+// Lets packs replace any asset by putting it at the same path
+// as the game uses, for things that have no redirect of their own
+#[derive(Debug, Clone)]
+pub struct AssetOverrides {
+    pub enabled: bool,
+    // If not empty, only assets under these can be overridden,
+    // even ones the default denylist has
+    pub allow: Vec<PathBuf>,
+    // Assets under these are never overridden
+    pub deny: Vec<PathBuf>,
+}
+
+// Replacing these breaks the game or makes no sense, like
+// the builtin packs or the files every pack has at its root,
+// unless the user explicitly allows them
+const DEFAULT_DENYLIST: [&str; 8] = [
+    "behavior_packs",
+    "resource_packs",
+    "skin_packs",
+    "world_templates",
+    "subpacks",
+    "manifest.json",
+    "pack_icon.png",
+    "contents.json",
+];
+impl Default for AssetOverrides {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+impl AssetOverrides {
+    fn allows(&self, asset_path: &Path) -> bool {
+        if !self.enabled || self.deny.iter().any(|p| asset_path.starts_with(p)) {
+            return false;
+        }
+        if !self.allow.is_empty() {
+            return self.allow.iter().any(|p| asset_path.starts_with(p));
+        }
+        !DEFAULT_DENYLIST.iter().any(|p| asset_path.starts_with(p))
+    }
+    fn allows_folder(&self, name: &OsStr) -> bool {
+        if !self.enabled || self.deny.iter().any(|p| p.as_os_str() == name) {
+            return false;
+        }
+        if !self.allow.is_empty() {
+            return self.allow.iter().any(|p| p.iter().next() == Some(name));
+        }
+        !DEFAULT_DENYLIST.iter().any(|p| OsStr::new(p) == name)
+    }
+}
+// Swapped as a whole on config loads, so asset loading threads
+// never wait for the watcher
+pub static REDIRECTS: LazyLock<ArcSwap<RedirectTable>> =
+    LazyLock::new(|| ArcSwap::from_pointee(RedirectTable::default()));
+
+impl Default for RedirectTable {
+    fn default() -> Self {
+        Self::new(vec![
+            Redirect::new("gui/dist/hbui/", "hbui/"),
+            Redirect::new("renderer/", "renderer/"),
+            Redirect::new("resource_packs/vanilla/cameras", "vanilla_cameras/"),
+            Redirect::new("skin_packs/persona", "custom_persona/"),
+        ])
+    }
+}
+impl Redirect {
+    pub fn new(game_prefix: impl Into<PathBuf>, pack_prefix: impl Into<PathBuf>) -> Self {
+        let mut pack_prefix: PathBuf = pack_prefix.into();
+        // asset_open glues paths together without adding separators
+        if !pack_prefix.as_os_str().as_bytes().ends_with(b"/") {
+            pack_prefix.as_mut_os_string().push("/");
+        }
+        Self {
+            game_prefix: game_prefix.into(),
+            pack_prefix,
+        }
+    }
+}
+impl RedirectTable {
+    pub fn new(mut redirects: Vec<Redirect>) -> Self {
+        sort_redirects(&mut redirects);
+        Self {
+            redirects,
+            overrides: AssetOverrides::default(),
+        }
+    }
+    // Add redirects on top of the current ones, replacing any
+    // that redirect the same game prefix
+    pub fn extend(&mut self, mut redirects: Vec<Redirect>) {
+        for redirect in &self.redirects {
+            if !redirects
+                .iter()
+                .any(|r| r.game_prefix == redirect.game_prefix)
+            {
+                redirects.push(redirect.clone());
+            }
+        }
+        sort_redirects(&mut redirects);
+        self.redirects = redirects;
+    }
+    pub fn set_overrides(&mut self, overrides: AssetOverrides) {
+        self.overrides = overrides;
+    }
+    /// Split an asset path into the pack folder it comes from
+    /// and the rest of the path
+    pub fn map<'a>(&'a self, asset_path: &'a Path) -> Option<(&'a Path, &'a Path)> {
+        let redirect = self.redirects.iter().find_map(|redirect| {
+            let rest = asset_path.strip_prefix(&redirect.game_prefix).ok()?;
+            Some((redirect.pack_prefix.as_path(), rest))
+        });
+        if redirect.is_none() && self.overrides.allows(asset_path) {
+            // Same path in the pack as in the game
+            return Some((Path::new(""), asset_path));
+        }
+        redirect
+    }
+    // If a top level folder of a pack has anything we redirect
+    pub fn is_pack_folder(&self, name: &OsStr) -> bool {
+        self.redirects
+            .iter()
+            .any(|redirect| redirect.pack_prefix.iter().next() == Some(name))
+            || self.overrides.allows_folder(name)
+    }
+}
+
+// The most specific prefix has to win, so check those first
+fn sort_redirects(redirects: &mut [Redirect]) {
+    redirects.sort_by_key(|redirect| std::cmp::Reverse(redirect.game_prefix.components().count()));
+}